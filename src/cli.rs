@@ -0,0 +1,267 @@
+use colored::Colorize;
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::manifest::{self, Target};
+
+#[derive(Debug)]
+pub struct Flag {
+    pub name: &'static str,
+    pub value: Option<&'static str>,
+    pub help: &'static str,
+}
+
+#[derive(Debug)]
+pub struct Command {
+    pub name: &'static str,
+    pub about: &'static str,
+    pub flags: &'static [&'static str],
+    pub required: &'static [&'static str],
+}
+
+pub const FLAGS: &[Flag] = &[
+    Flag { name: "-i",           value: Some("<input>"),                 help: "Folder with the .asm sources, for disasm the binary to read." },
+    Flag { name: "-o",           value: Some("<output_file>"),           help: "Output file." },
+    Flag { name: "-cfg",         value: Some("<file_path>"),             help: "File with more flags, flags on the command line override it." },
+    Flag { name: "-manifest",    value: Some("<file_path>"),             help: "Project manifest, defaults to vm64.toml when no input is given." },
+    Flag { name: "-target",      value: Some("<name>"),                  help: "Manifest target, defaults to its `default` or only target." },
+    Flag { name: "-profile",     value: Some("<debug|release>"),         help: "Manifest profile layered over the target, defaults to debug." },
+    Flag { name: "-D",           value: Some("<NAME>[=<value>]"),        help: "Defines a name for #if and word replacement, repeatable, the value defaults to 1." },
+    Flag { name: "-format",      value: Some("<bin|ihex|srec|vm64>"),    help: "Output format, defaults to a flat binary." },
+    Flag { name: "-align",       value: Some("<alignment in hex>"),      help: "Used for aligning labels in absolute mode." },
+    Flag { name: "-drive",       value: Some("<manifest>"),              help: "Builds a whole drive image described by the manifest into the output file." },
+    Flag { name: "-inter",       value: Some("<output_file>"),           help: "Generates intermediate represantation." },
+    Flag { name: "-inter-data",  value: Some("<bytes>"),                 help: "Bytes of image and byte data shown in the listing, 0 shows all." },
+    Flag { name: "-map",         value: Some("<output_file>"),           help: "Generates a symbol map with absolute addresses." },
+    Flag { name: "-debug",       value: Some("<output_file>"),           help: "Generates debug info mapping addresses to source lines." },
+    Flag { name: "-json",        value: Some("<output_file>"),           help: "Generates a JSON description of the program for tooling." },
+    Flag { name: "-limit",       value: Some("<max_instructions>"),      help: "Instructions executed before the simulator stops, defaults to 1000000." },
+    Flag { name: "-stack",       value: Some("<address in hex>"),        help: "Initial stack pointer, defaults to the alignment." },
+    Flag { name: "-dump",        value: Some("<address>:<length>"),      help: "Memory range in hex dumped when the program stops." },
+    Flag { name: "-fb",          value: Some("<base>:<w>x<h>:<format>"), help: "Framebuffer device, defaults to 3fea0700:800x600:rgb8." },
+    Flag { name: "-snapshot",    value: Some("<png_file>"),              help: "Writes the framebuffer as PNG when the program stops." },
+    Flag { name: "-snapshot-at", value: Some("<instructions>"),          help: "Takes the snapshot after this many instructions instead." },
+    Flag { name: "-lint",        value: Some("<rule>=<level>,..."),      help: "Lint levels off, warn or error, `all` sets every rule. Rules: unreachable, unused-label, unused-data, stack, uninit, jump-to-data." },
+    Flag { name: "--optimise",   value: None,                            help: "Runs the peephole optimiser and reports what it changed." },
+    Flag { name: "--pic",        value: None,                            help: "Position-independent code, label references are relative to the program counter." },
+    Flag { name: "--reloc",      value: None,                            help: "Writes a relocation table to <output_file>.reloc, vm64 executables embed it." },
+    Flag { name: "--watch",      value: None,                            help: "Reassembles whenever the sources or their assets change." },
+    Flag { name: "--check",      value: None,                            help: "Lists the files that aren't formatted and fails instead of writing." },
+    Flag { name: "--help",       value: None,                            help: "Prints this help." },
+];
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "build",
+        about: "Assembles the input folder, the default when no command is given.",
+        flags: &["-i", "-o", "-cfg", "-manifest", "-target", "-profile", "-D", "-format", "-align", "-drive", "-inter", "-inter-data", "-map", "-debug", "-json", "--optimise", "--pic", "--reloc", "--watch", "--help"],
+        required: &["-o"],
+    },
+    Command {
+        name: "run",
+        about: "Assembles the input folder and runs it in the headless simulator, then dumps its state.",
+        flags: &["-i", "-cfg", "-manifest", "-target", "-profile", "-D", "-align", "-limit", "-stack", "-dump", "-fb", "-snapshot", "-snapshot-at", "--optimise", "--pic", "--help"],
+        required: &["-i"],
+    },
+    Command {
+        name: "test",
+        about: "Runs the #test blocks of the input folder in the simulator.",
+        flags: &["-i", "-cfg", "-manifest", "-target", "-profile", "-D", "-align", "-limit", "--help"],
+        required: &["-i"],
+    },
+    Command {
+        name: "check",
+        about: "Assembles the input folder and reports errors and lint warnings without writing anything.",
+        flags: &["-i", "-cfg", "-manifest", "-target", "-profile", "-D", "-align", "-lint", "--pic", "--watch", "--help"],
+        required: &["-i"],
+    },
+    Command {
+        name: "fmt",
+        about: "Formats every .asm file of the input and include folders in place.",
+        flags: &["-i", "-cfg", "-manifest", "-target", "-profile", "--check", "--help"],
+        required: &["-i"],
+    },
+    Command {
+        name: "disasm",
+        about: "Disassembles a flat binary or vm64 executable, to stdout unless -o is given.",
+        flags: &["-i", "-o", "-cfg", "-align", "--help"],
+        required: &["-i"],
+    },
+    Command {
+        name: "lsp",
+        about: "Serves the language server protocol over stdin and stdout, -align sets the hover addresses.",
+        flags: &["-align", "--help"],
+        required: &[],
+    },
+];
+
+pub type Options = HashMap<&'static str, String>;
+
+fn flag(name: &str) -> Option<&'static Flag> {
+    let name = if name == "-h" { "--help" } else { name };
+    FLAGS.iter().find(|f| f.name == name)
+}
+
+/// Parses `<flag> <value>` pairs, `source` names where they came from for errors. Defines
+/// repeat, so they're returned apart in the order given.
+fn parse_flags(command: &Command, args: &[String], source: &str) -> Result<(Options, Vec<String>), String> {
+    let mut options = Options::new();
+    let mut defines = Vec::new();
+
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i].as_str();
+        let found = match flag(arg) {
+            Some(f) if command.flags.contains(&f.name) => f,
+            Some(_) => return Err(format!("`{}` in {} isn't a flag of `asm {}`.", arg, source, command.name)),
+            None =>    return Err(format!("Unknown flag `{}` in {}.", arg, source)),
+        };
+
+        match found.value {
+            Some(value) => {
+                match args.get(i + 1) {
+                    Some(v) if flag(v).is_none() && found.name == "-D" => { defines.push(v.clone()); }
+                    Some(v) if flag(v).is_none() => { options.insert(found.name, v.clone()); }
+
+                    _ => return Err(format!("`{}` in {} expects {}.", found.name, source, value)),
+                }
+                i += 2;
+            }
+            None => {
+                options.insert(found.name, String::new());
+                i += 1;
+            }
+        }
+    }
+
+    Ok((options, defines))
+}
+
+/// Resolves the command, its options and the manifest target from the arguments after the
+/// program name. Layers are applied in order, each overriding the flags of the previous one:
+/// the manifest target, then the `-cfg` file, then the command line.
+pub fn parse(args: &[String]) -> Result<(&'static Command, Options, Target), String> {
+    let (command, args) = match COMMANDS.iter().find(|c| args.first() == Some(&c.name.to_owned())) {
+        Some(command) => (command, &args[1..]),
+        None if args.first().is_some_and(|a| a.starts_with("-")) => (&COMMANDS[0], args),
+        None => return Err(format!("Unknown command `{}`, expected build, run, test, check, fmt, disasm or lsp.", args.first().map(|a| a.as_str()).unwrap_or(""))),
+    };
+
+    let (cli, cli_defines) = parse_flags(command, args, "the arguments")?;
+    if cli.contains_key("--help") { return Ok((command, cli, Target::default())) }
+
+    let (mut cfg, mut cfg_defines) = (Options::new(), Vec::new());
+    if let Some(path) = cli.get("-cfg") {
+        let text = fs::read_to_string(path).map_err(|_| format!("Unable to read config file `{}`.", path))?;
+        let text = text.replace(",", " ").split_whitespace().map(|s| s.to_owned()).collect::<Vec<String>>();
+
+        (cfg, cfg_defines) = parse_flags(command, &text, &format!("`{}`", path))?;
+        if cfg.contains_key("-cfg") { return Err(format!("`{}` can't include another config file.", path)) }
+    }
+
+    let given = |name: &str| cli.get(name).or(cfg.get(name));
+    let explicit = ["-manifest", "-target", "-profile"].iter().any(|f| given(f).is_some());
+    let input = command.flags.contains(&"-manifest") && given("-i").is_none() && given("-drive").is_none() && Path::new(manifest::MANIFEST).exists();
+
+    let mut target = Target::default();
+    if explicit || input {
+        let path = given("-manifest").map(|p| p.as_str()).unwrap_or(manifest::MANIFEST);
+        target = manifest::load(path, given("-target").map(|t| t.as_str()), given("-profile").map(|p| p.as_str()).unwrap_or(manifest::DEFAULT_PROFILE))?;
+    }
+
+    for define in cfg_defines.iter().chain(cli_defines.iter()) {
+        match define.split_once("=").unwrap_or((define, "1")) {
+            ("", _) => return Err(format!("`-D` expects <NAME>[=<value>], got `{}`.", define)),
+            (name, value) => { target.defines.insert(name.to_owned(), value.to_owned()); }
+        }
+    }
+
+    let mut options = target.options.clone();
+    options.extend(cfg);
+    options.extend(cli);
+
+    for name in command.required.iter() {
+        if !options.contains_key(name) {
+            return Err(format!("`asm {}` needs {} {}.", command.name, name, flag(name).unwrap().value.unwrap()));
+        }
+    }
+
+    Ok((command, options, target))
+}
+
+pub fn usage(command: &Command) -> String {
+    let mut buf = format!("Usage: asm {} [flags]\n\n    {}\n\n", command.name, command.about);
+
+    for name in command.flags.iter() {
+        let flag = flag(name).unwrap();
+        buf.push_str(&format!("    {:<12} {:<26} {}\n", flag.name, flag.value.unwrap_or(""), flag.help));
+    }
+
+    buf
+}
+
+/// Prints an argument error with a pointer to the help and exits.
+pub fn fail(command: Option<&Command>, error: &str) -> ! {
+    eprintln!("{}: {}", "Error".red().bold(), error.bold());
+    match command {
+        Some(command) => eprintln!("Run `asm {} --help` for the accepted flags.", command.name),
+        None =>          eprintln!("Run `asm help` for the available commands."),
+    }
+
+    std::process::exit(2)
+}
+
+/// Unwraps a flag value or exits with its error.
+pub fn or_fail<T>(command: &Command, result: Result<T, String>) -> T {
+    result.unwrap_or_else(|e| fail(Some(command), &e))
+}
+
+/// Parses a hex flag value like `-align`, `_` separators are allowed.
+pub fn hex(options: &Options, name: &str) -> Result<Option<usize>, String> {
+    options.get(name).map(|v| usize::from_str_radix(&v.replace("_", ""), 16).map_err(|_| format!("Invalid hex number `{}` for {}.", v, name))).transpose()
+}
+
+/// Whether a switch like `--pic` is on, a manifest can also set it to `false`.
+pub fn switch(options: &Options, name: &str) -> bool {
+    options.get(name).is_some_and(|v| v != "false")
+}
+
+pub fn number(options: &Options, name: &str) -> Result<Option<usize>, String> {
+    options.get(name).map(|v| v.parse::<usize>().map_err(|_| format!("Invalid number `{}` for {}.", v, name))).transpose()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(s: &str) -> Vec<String> {
+        s.split(" ").map(|s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn flag_errors() {
+        assert!(parse(&args("build -i src -o")).unwrap_err().contains("expects <output_file>"));
+        assert!(parse(&args("build -i src -o out -x")).unwrap_err().contains("Unknown flag `-x`"));
+        assert!(parse(&args("build -i src -o out -stack 100")).unwrap_err().contains("isn't a flag of `asm build`"));
+        assert!(parse(&args("disasm -o out")).unwrap_err().contains("needs -i"));
+        assert!(parse(&args("frobnicate")).is_err());
+
+        let (command, options, _) = parse(&args("-i src -o out")).unwrap();
+        assert_eq!((command.name, options["-o"].as_str()), ("build", "out"));
+        assert!(parse(&args("run -h")).unwrap().1.contains_key("--help"));
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let path = std::env::temp_dir().join(format!("vm64-cli-{}.cfg", std::process::id()));
+        fs::write(&path, "-i src\n-o cfg.bin,\n-align 00C00000\n-D LIVES=3 -D DEBUG=1").unwrap();
+
+        let result = parse(&args(&format!("build -cfg {} -o cli.bin -D DEBUG=0 -D DEMO -D MSG=a,b", path.display())));
+        fs::remove_file(&path).unwrap();
+
+        let (_, options, target) = result.unwrap();
+        assert_eq!((options["-i"].as_str(), options["-o"].as_str(), options["-align"].as_str()), ("src", "cli.bin", "00C00000"));
+        assert_eq!((target.defines["LIVES"].as_str(), target.defines["DEBUG"].as_str(), target.defines["DEMO"].as_str()), ("3", "0", "1"));
+        assert_eq!(target.defines["MSG"], "a,b");
+    }
+}
@@ -0,0 +1,169 @@
+//! Conditional assembly, evaluated before lexing.
+//!
+//!     #ifdef DEBUG            #if LIVES > 3 && !defined(DEMO)
+//!     ...                     ...
+//!     #elif VERSION == 2      #else
+//!     ...                     ...
+//!     #endif                  #endif
+//!
+//! Names are looked up in the defines, undefined names are 0 and `true`/`false` are 1 and 0.
+//! An `#if` comparing two registers is structured control flow and is left for the lexer.
+
+use std::collections::HashMap;
+
+use crate::{error, expr::eval, resolve_arg, tokenize, Arg, Control, Line};
+
+#[derive(Debug)]
+enum Frame {
+    /// A `#if` the lexer lowers, its `#else` and `#endif` are kept too.
+    Flow,
+    /// A constant `#if`: whether the enclosing region is assembled, whether a branch was taken
+    /// already, whether the current branch is assembled, whether `#else` was seen, and the
+    /// opening line.
+    Asm { outer: bool, taken: bool, active: bool, done: bool, line: Line },
+}
+
+/// `#if` conditions of structured control flow, like `r0 < r1`.
+fn is_flow(args: &[String]) -> bool {
+    let reg = |a: &str| matches!(resolve_arg(a.to_owned()), Ok(Arg::Ureg(_) | Arg::Freg(_)));
+    args.len() == 3 && reg(&args[0]) && reg(&args[2])
+}
+
+/// Whether a constant condition names a register that isn't also a define, like `r0 == 5`.
+fn names_register(expr: &str, defines: &HashMap<String, String>) -> bool {
+    expr.split(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+        .any(|w| !defines.contains_key(w) && matches!(resolve_arg(w.to_owned()), Ok(Arg::Ureg(_) | Arg::Freg(_))))
+}
+
+/// Blanks the lines of disabled regions and the conditional directives themselves, keeping
+/// line numbers. Errors are reported at the directive.
+pub fn strip_disabled(source: &str, path: &str, defines: &HashMap<String, String>) -> String {
+    let mut frames: Vec<Frame> = Vec::new();
+    let active = |frames: &[Frame]| frames.iter().all(|f| !matches!(f, Frame::Asm { active: false, .. }));
+
+    let lines = source.split("\n").enumerate().map(|(n, text)| {
+        let code = text.split(";").next().unwrap();
+        let parts = tokenize(code);
+        let directive = parts.first().map(|d| d.to_lowercase()).unwrap_or_default();
+        let line: Line = (Control::None, Vec::new(), parts.first().cloned().unwrap_or_default(), parts.iter().skip(1).cloned().collect(), path.to_owned(), n + 1);
+        let expr = code.trim().get(directive.len()..).unwrap_or("").trim();
+
+        let condition = |frames: &[Frame]| {
+            let result = match directive.as_str() {
+                "#ifdef" =>     Ok(defines.contains_key(expr) as i64),
+                "#ifndef" =>    Ok(!defines.contains_key(expr) as i64),
+
+                _ if names_register(expr, defines) => Err(String::from("Registers can't be used in a constant condition, a runtime `#if` compares two registers.")),
+                _ => eval(expr, defines),
+            };
+
+            match result {
+                _ if !active(frames) => false,
+                Ok(n) => n != 0,
+                Err(e) => { error(line.clone(), &e); false }
+            }
+        };
+
+        let keep = match (directive.as_str(), frames.last_mut()) {
+            ("#if", _) if is_flow(&line.3) => {
+                frames.push(Frame::Flow);
+                return if active(&frames) { text } else { "" };
+            }
+            ("#if" | "#ifdef" | "#ifndef", _) => {
+                let (outer, taken) = (active(&frames), condition(&frames));
+                frames.push(Frame::Asm { outer, taken: taken || !outer, active: taken, done: false, line: line.clone() });
+                false
+            }
+            ("#elif", Some(Frame::Asm { done: false, .. })) => {
+                let value = condition(&frames[..frames.len() - 1]);
+                if let Some(Frame::Asm { taken, active, .. }) = frames.last_mut() {
+                    *active = !*taken && value;
+                    *taken |= value;
+                }
+                false
+            }
+            ("#else", Some(Frame::Asm { outer, taken, active, done: done @ false, .. })) => {
+                (*active, *taken, *done) = (*outer && !*taken, true, true);
+                false
+            }
+            ("#elif" | "#else", Some(Frame::Asm { .. })) => {
+                error(line.clone(), &format!("`{}` after `#else`.", directive));
+                false
+            }
+            ("#elif", Some(Frame::Flow)) => {
+                error(line.clone(), "A runtime `#if` has no `#elif`, nest another `#if` in its `#else`.");
+                false
+            }
+            ("#elif", _) => {
+                error(line.clone(), "`#elif` without `#if`.");
+                false
+            }
+            ("#endif", Some(Frame::Asm { .. })) => {
+                frames.pop();
+                false
+            }
+            ("#endif", Some(Frame::Flow)) => {
+                frames.pop();
+                true
+            }
+
+            _ => true,
+        };
+
+        if keep && active(&frames) { text } else { "" }
+    }).collect::<Vec<&str>>();
+
+    for frame in frames.into_iter() {
+        if let Frame::Asm { line, .. } = frame {
+            error(line, "`#if` without `#endif`.");
+        }
+    }
+
+    lines.join("\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect_errors;
+
+    #[test]
+    fn regions() {
+        let source = indoc::indoc! {"
+            #ifdef DEBUG
+            a
+            #if LIVES == 3
+            b
+            #elif 1
+            c
+            #else
+            d
+            #endif
+            #else
+            e
+            #endif
+            #if r0 == r1
+            f
+            #else
+            #ifndef DEBUG
+            g
+            #endif
+            #endif"};
+        let defines = HashMap::from([(String::from("DEBUG"), String::new()), (String::from("LIVES"), String::from("3"))]);
+
+        let stripped = strip_disabled(source, "test.asm", &defines);
+        assert_eq!(stripped.split("\n").filter(|l| !l.is_empty()).collect::<Vec<&str>>(), vec!["a", "b", "#if r0 == r1", "f", "#else", "#endif"]);
+        assert_eq!(stripped.split("\n").count(), source.split("\n").count());
+
+        let (_, errors) = collect_errors(|| strip_disabled("#if 1 +\n#else\n#elif 1\n#endif\n#if r0 == 5\n#elif f1\n#endif\n#if r0 == r1\n#elif r2 == r3\n#endif\n#ifdef A\n", "test.asm", &HashMap::new()));
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![
+            (1, "Incomplete expression."),
+            (3, "`#elif` after `#else`."),
+            (5, "Registers can't be used in a constant condition, a runtime `#if` compares two registers."),
+            (6, "Registers can't be used in a constant condition, a runtime `#if` compares two registers."),
+            (9, "A runtime `#if` has no `#elif`, nest another `#if` in its `#else`."),
+            (11, "`#if` without `#endif`."),
+        ]);
+    }
+}
@@ -0,0 +1,70 @@
+use std::fs;
+
+use crate::{map::{Symbol, SymbolKind}, Line};
+
+/// Builds the debug info sidecar.
+///
+/// The format is line based, every number is hex and ranges are half open:
+///
+///     vm64-debug 1
+///     file  <index> <path>
+///     line  <start> <end> <file index> <line>
+///     scope <start> <end> <label>
+pub fn debug_info(instructions: &[Line], symbols: &[Symbol], align: usize) -> String {
+    let mut files: Vec<String> = Vec::new();
+    let mut lines = String::new();
+
+    let mut index = align;
+    for inst in instructions.iter() {
+        if inst.1.is_empty() { continue }
+
+        let file = match files.iter().position(|f| *f == inst.4) {
+            Some(file) => file,
+            None => { files.push(inst.4.clone()); files.len() - 1 }
+        };
+
+        lines.push_str(&format!("line  {:08x} {:08x} {:x} {:x}\n", index, index + inst.1.len(), file, inst.5));
+
+        index += inst.1.len();
+    }
+
+    let mut buf = String::from("vm64-debug 1\n");
+    for (i, file) in files.iter().enumerate() {
+        buf.push_str(&format!("file  {:x} {}\n", i, file));
+    }
+    buf.push_str(&lines);
+    for s in symbols.iter().filter(|s| s.kind == SymbolKind::Label) {
+        buf.push_str(&format!("scope {:08x} {:08x} {}\n", s.address, s.address + s.size, s.name));
+    }
+
+    buf
+}
+
+pub fn write_debug_info(path: String, instructions: &[Line], symbols: &[Symbol], align: usize) {
+    fs::write(path, debug_info(instructions, symbols, align)).unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::collect_symbols, tests::line, Control};
+
+    #[test]
+    fn ranges_and_scopes() {
+        let instructions = vec![
+            line(Control::Label, vec![], "loop:", &[], 1),
+            line(Control::Inst, vec![0x40, 0x00], "inc", &["r0"], 2),
+            line(Control::ReqLabel, vec![0x50, 0x00, 0x00, 0x01, 0x00], "jmp", &["loop"], 3),
+        ];
+        let symbols = collect_symbols(&instructions, 0x100);
+
+        assert_eq!(debug_info(&instructions, &symbols, 0x100), concat!(
+            "vm64-debug 1\n",
+            "file  0 main.asm\n",
+            "line  00000100 00000102 0 2\n",
+            "line  00000102 00000107 0 3\n",
+            "scope 00000100 00000107 loop\n",
+        ));
+    }
+}
@@ -0,0 +1,129 @@
+//! Built-in definitions of the VM's ports, syscall numbers and memory map.
+//!
+//!     #import vm64            ; the latest version
+//!     #import vm64 1          ; pinned, later additions aren't defined
+//!
+//!     out r1 PORT_CONSOLE
+//!     syscall SYS_WRITE       ; mov r0 SYS_WRITE, syscall
+//!
+//! The names are replaced in the importing file only. Every definition records the version
+//! it was added in, so a pinned import keeps meaning the same thing as the set grows.
+
+use std::collections::HashMap;
+
+use crate::{drive, error, fb, replace_words, tokenize, Control, Line};
+
+/// Name of the built-in module.
+pub const MODULE: &str = "vm64";
+
+/// Version of the newest definitions, an unpinned import gets these.
+pub const LATEST: u32 = 1;
+
+/// Register `syscall <number>` loads the number into. Arguments go in `r1` upwards and the
+/// result comes back in `r0`.
+pub const SYSCALL_REG: &str = "r0";
+
+/// (version added, name, value, description)
+pub const DEFINITIONS: &[(u32, &str, u64, &str)] = &[
+    (1, "PORT_CONSOLE",     0x0001, "Console output, writes the low byte as a character."),
+    (1, "PORT_KEYBOARD",    0x0002, "Keyboard input, reads the next key or 0."),
+    (1, "PORT_TIMER",       0x0003, "Timer, reads the cycle count and writes set the interval."),
+    (1, "PORT_DRIVE",       0x0004, "Drive control, writes select the sector to transfer."),
+    (1, "PORT_FB",          0x0005, "Framebuffer control, writing 1 presents the frame."),
+
+    (1, "SYS_EXIT",         0x00,   "Stops the program with the exit code in r1."),
+    (1, "SYS_WRITE",        0x01,   "Writes r3 bytes from address r2 to handle r1."),
+    (1, "SYS_READ",         0x02,   "Reads up to r3 bytes from handle r1 to address r2."),
+    (1, "SYS_OPEN",         0x03,   "Opens the file named at address r1, returns a handle."),
+    (1, "SYS_CLOSE",        0x04,   "Closes handle r1."),
+    (1, "SYS_TIME",         0x05,   "Returns the cycle count."),
+
+    (1, "FB_BASE",          fb::DEFAULT_BASE as u64,    "Start of the framebuffer."),
+    (1, "FB_WIDTH",         fb::DEFAULT_WIDTH as u64,   "Framebuffer width in pixels."),
+    (1, "FB_HEIGHT",        fb::DEFAULT_HEIGHT as u64,  "Framebuffer height in pixels."),
+    (1, "FB_BYTES",         (fb::DEFAULT_WIDTH * fb::DEFAULT_HEIGHT * 3) as u64, "Size of the framebuffer in bytes, RGB8."),
+    (1, "DRIVE_BOOT",       drive::BOOT_OFFSET as u64,  "Drive offset the VM boots from."),
+    (1, "DRIVE_SECTOR",     drive::SECTOR as u64,       "Drive sector size in bytes."),
+];
+
+/// The definition called `name`, in any version.
+pub fn lookup(name: &str) -> Option<&'static (u32, &'static str, u64, &'static str)> {
+    DEFINITIONS.iter().find(|d| d.1 == name)
+}
+
+/// Definitions of `version` as the text they replace names with.
+fn definitions(version: u32) -> HashMap<String, String> {
+    DEFINITIONS.iter().filter(|d| d.0 <= version).map(|d| (d.1.to_owned(), format!("&{:x}", d.2))).collect()
+}
+
+/// The version an `#import` line asks for.
+fn version(args: &[String]) -> Result<u32, String> {
+    match args {
+        [module, ..] if module != MODULE => Err(format!("Unknown module `{}`, the built-in one is `{}`.", module, MODULE)),
+        [_] => Ok(LATEST),
+        [_, version] => match version.parse::<u32>() {
+            Ok(v @ 1..) if v <= LATEST => Ok(v),
+
+            _ => Err(format!("Unknown version `{}` of `{}`, versions are 1 to {}.", version, MODULE, LATEST)),
+        },
+
+        _ => Err(format!("Expected `#import {} [version]`.", MODULE)),
+    }
+}
+
+/// Blanks the `#import` lines of `source` and replaces the names they define.
+pub fn apply(source: &str, path: &str) -> String {
+    let mut words: Option<HashMap<String, String>> = None;
+
+    let lines = source.split("\n").enumerate().map(|(n, text)| {
+        let parts = tokenize(text.split(";").next().unwrap());
+        if !parts.first().is_some_and(|p| p.eq_ignore_ascii_case("#import")) { return text }
+
+        let line: Line = (Control::None, Vec::new(), parts[0].clone(), parts[1..].to_vec(), path.to_owned(), n + 1);
+        match version(&line.3) {
+            Ok(_) if words.is_some() => error(line, "Imported twice."),
+            Ok(version) => words = Some(definitions(version)),
+            Err(e) => error(line, &e),
+        }
+        ""
+    }).collect::<Vec<&str>>();
+
+    match words {
+        Some(words) => lines.iter().map(|l| replace_words(l, "_.", &words)).collect::<Vec<String>>().join("\n"),
+        None => lines.join("\n"),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collect_errors, lex_source};
+
+    #[test]
+    fn imports() {
+        let source = "#import vm64 1 ; ports\nout r1 PORT_CONSOLE\nmov r0 FB_BASE ; PORT_TIMER\nmov r2 MY_PORT_CONSOLE";
+        assert_eq!(apply(source, "a.asm"), "\nout r1 &1\nmov r0 &3fea0700 ; &3\nmov r2 MY_PORT_CONSOLE");
+        assert_eq!(apply("out r1 PORT_CONSOLE", "a.asm"), "out r1 PORT_CONSOLE");
+
+        let (_, errors) = collect_errors(|| apply("#import vm32\n#import vm64 9\n#import vm64\n#import vm64", "a.asm"));
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![
+            (1, "Unknown module `vm32`, the built-in one is `vm64`."),
+            (2, "Unknown version `9` of `vm64`, versions are 1 to 1."),
+            (4, "Imported twice."),
+        ]);
+    }
+
+    #[test]
+    fn ports_and_syscalls() {
+        let bytes = |source: &str| {
+            let (instructions, errors) = collect_errors(|| lex_source(&apply(source, "main.asm"), "main.asm"));
+            assert!(errors.is_empty(), "{:?}", errors);
+            instructions.into_iter().flat_map(|l| l.1).collect::<Vec<u8>>()
+        };
+
+        assert_eq!(bytes("#import vm64\nout r0 PORT_CONSOLE\n"), bytes("out r0 &1\n"));
+        assert_eq!(bytes("#import vm64\nsyscall SYS_WRITE\n"), bytes("mov r0 &1\nsyscall\n"));
+        assert_eq!(bytes("mov r0 &1\nsyscall\n").last(), Some(&0x80));
+    }
+}
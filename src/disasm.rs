@@ -0,0 +1,248 @@
+use std::collections::HashMap;
+
+use crate::{format, reloc, sim};
+
+/// Bytes per `db` line for data and undecodable bytes.
+const DB_ROW: usize = 8;
+
+fn be(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |n, b| (n << 8) | *b as usize)
+}
+
+fn label(address: usize) -> String {
+    format!("l_{:08x}", address)
+}
+
+/// Absolute jump target of the instruction, if it has a literal one.
+fn target(bytes: &[u8]) -> Option<usize> {
+    match bytes[0] {
+        0x50 | 0x5E | 0x60 =>                           Some(be(&bytes[1..5])),
+        0x52 | 0x54 | 0x56 | 0x58 | 0x5A | 0x5C =>      Some(be(&bytes[3..7])),
+
+        _ => None,
+    }
+}
+
+/// `mov`, `mva` and `mvd` in the operand order `resolve_inst` accepts them.
+fn memory(op: u8, bytes: &[u8]) -> String {
+    let (name, kind) = match op {
+        0x07..=0x0E => ("mov", op - 0x07),
+        0x0F..=0x16 => ("mva", op - 0x0F),
+        0x17..=0x1A => ("mov", op - 0x17 + 8),
+        0x1B..=0x1E => ("mva", op - 0x1B + 8),
+        0x1F..=0x26 => ("mvd", op - 0x1F),
+
+        _ =>           ("mvd", op - 0x27 + 8),
+    };
+    let r = |i: usize| format!("r{:x}", bytes[i]);
+    let x = |i: usize| format!("{}{:x}", if kind % 2 == 1 { "f" } else { "r" }, bytes[i]);
+
+    let args = match kind {
+        0 | 1 => format!("{} {} &{:x}", x(1), bytes[2], be(&bytes[3..7])),
+        2 | 3 => format!("&{:x} {} {}", be(&bytes[1..5]), x(6), bytes[5]),
+        4 | 5 => format!("{} {} {}", r(1), x(3), bytes[2]),
+        6 | 7 => format!("{} {} {}", x(1), bytes[2], r(3)),
+        8 | 9 => format!("&{:x} {} {} {}", be(&bytes[4..8]), r(1), x(3), bytes[2]),
+
+        _ =>     format!("{} {} &{:x} {}", x(1), bytes[2], be(&bytes[4..8]), r(3)),
+    };
+
+    format!("{} {}", name, args)
+}
+
+/// Decodes one instruction, returns its text and length. Jump targets found in `labels`
+/// are written as the label.
+pub fn instruction(bytes: &[u8], labels: &HashMap<usize, String>) -> Option<(String, usize)> {
+    let op = *bytes.first()?;
+    let len = sim::length(op)?;
+    if bytes.len() < len { return None }
+
+    let r = |i: usize| format!("r{:x}", bytes[i]);
+    let f = |i: usize| format!("f{:x}", bytes[i]);
+    let to = |n: usize| labels.get(&n).cloned().unwrap_or_else(|| format!("&{:x}", n));
+
+    let text = match op {
+        0x00 => String::from("nop"),
+
+        0x01 => format!("mov {} {}", r(1), r(2)),
+        0x02 => format!("mov {} {}", f(1), f(2)),
+        0x03 => format!("mov {} {}", f(1), r(2)),
+        0x04 => format!("mov {} {}", r(1), f(2)),
+        0x05 => format!("mov {} &{:x}", r(1), be(&bytes[2..10])),
+        0x06 => format!("mov {} &{:x}", f(1), be(&bytes[2..10])),
+        0x07..=0x2A => memory(op, bytes),
+
+        0x30..=0x39 => {
+            let name = ["add", "sub", "mul", "div", "mod"][(op as usize - 0x30) / 2];
+            let x: &dyn Fn(usize) -> String = if op % 2 == 1 { &f } else { &r };
+            format!("{} {} {} {}", name, x(1), x(2), x(3))
+        }
+        0x3A..=0x3E => format!("{} {} {} {}", ["shl", "shr", "and", "or", "xor"][op as usize - 0x3A], r(1), r(2), r(3)),
+        0x3F => format!("not {} {}", r(1), r(2)),
+
+        0x40 => format!("inc {}", r(1)),
+        0x41 => format!("dec {}", r(1)),
+        0x42 => format!("psh {}", r(1)),
+        0x43 => format!("psh {}", f(1)),
+        0x44 => format!("pop {}", r(1)),
+        0x45 => format!("pop {}", f(1)),
+        0x46 => format!("adc {}", r(1)),
+        0x47 => format!("sbc {}", r(1)),
+        0x48 => String::from("scf"),
+        0x49 => String::from("ccf"),
+
+        0x50 => format!("jmp {}", to(be(&bytes[1..5]))),
+        0x51 => format!("jmp {}", r(1)),
+        0x52..=0x5D => {
+            let name = ["jlg", "jpe", "jne"][(op as usize - 0x52) / 4];
+            let x: &dyn Fn(usize) -> String = if (op - 0x52) % 4 >= 2 { &f } else { &r };
+            let dest = if op.is_multiple_of(2) { to(be(&bytes[3..7])) } else { r(3) };
+            format!("{} {} {} {}", name, x(1), x(2), dest)
+        }
+        0x5E => format!("jpc {}", to(be(&bytes[1..5]))),
+        0x5F => format!("jpc {}", r(1)),
+        0x60 => format!("jnc {}", to(be(&bytes[1..5]))),
+        0x61 => format!("jnc {}", r(1)),
+
+        0x70 => String::from("hlt"),
+        0x71 => String::from("wit 0"),
+        0x72 => format!("wit {}", r(1)),
+        0x73 => format!("gst {}", r(1)),
+        0x74 => format!("gpc {}", r(1)),
+
+        0x80 => String::from("syscall"),
+        0x81 => String::from("sysret"),
+        0x82 => format!("memcpy &{:x} &{:x} {}", be(&bytes[5..9]), be(&bytes[1..5]), be(&bytes[9..12])),
+        0x83 => format!("memcpy {} {} {}", r(2), r(1), be(&bytes[3..6])),
+        0x84 => format!("memcpy {} {} {}", r(2), r(1), r(3)),
+
+        0x90 => format!("out {} &{:x}", r(1), be(&bytes[2..4])),
+        0x91 => format!("out {} {}", r(1), r(2)),
+        0x92 => format!("in {} &{:x}", r(1), be(&bytes[2..4])),
+        0x93 => format!("in {} {}", r(1), r(2)),
+
+        0xA0 => format!("grapcpy {} &{:x} {} {} {} {}", r(5), be(&bytes[1..5]), be(&bytes[10..12]), be(&bytes[12..14]), be(&bytes[8..10]), be(&bytes[6..8])),
+        0xA1 => format!("grapcpy {} {} {} {} {} {}", r(2), r(1), r(5), r(6), r(4), r(3)),
+
+        _ => return None,
+    };
+
+    Some((text, len))
+}
+
+fn line(buf: &mut String, text: &str, address: usize, bytes: &[u8]) {
+    buf.push_str(&format!("    {:<32}; {:08x}  {}\n", text, address, bytes.iter().map(|n| format!("{:02x}", n)).collect::<Vec<String>>().join(" ")));
+}
+
+fn data(buf: &mut String, bytes: &[u8], address: usize) {
+    for (i, chunk) in bytes.chunks(DB_ROW).enumerate() {
+        line(buf, &format!("db {}", chunk.iter().map(|n| format!("&{:02x}", n)).collect::<Vec<String>>().join(" ")), address + i * DB_ROW, chunk);
+    }
+}
+
+/// Disassembles code loaded at `address`. Jump targets inside the code get `l_<address>` labels,
+/// bytes that don't decode are kept as `db`, so the output assembles back to the same bytes.
+fn code(buf: &mut String, bytes: &[u8], address: usize) {
+    let mut labels = HashMap::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        match sim::length(bytes[i]).filter(|l| i + l <= bytes.len()) {
+            Some(len) => {
+                if let Some(t) = target(&bytes[i..]).filter(|t| (address..address + bytes.len()).contains(t)) {
+                    labels.insert(t, label(t));
+                }
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+
+    let mut i = 0;
+    let mut unknown: Vec<u8> = Vec::new();
+    while i < bytes.len() {
+        let decoded = instruction(&bytes[i..], &labels);
+
+        if !unknown.is_empty() && (decoded.is_some() || labels.contains_key(&(address + i)) || unknown.len() == DB_ROW) {
+            data(buf, &unknown, address + i - unknown.len());
+            unknown.clear();
+        }
+        if let Some(name) = labels.get(&(address + i)) {
+            buf.push_str(&format!("{}:\n", name));
+        }
+
+        match decoded {
+            Some((text, len)) => {
+                line(buf, &text, address + i, &bytes[i..i + len]);
+                i += len;
+            }
+            None => {
+                unknown.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    if !unknown.is_empty() {
+        data(buf, &unknown, address + bytes.len() - unknown.len());
+    }
+}
+
+/// Disassembles a flat binary loaded at `align`, or a vm64 executable using its section table.
+pub fn disassemble(bytes: &[u8], align: usize) -> Result<String, &'static str> {
+    if !bytes.starts_with(format::MAGIC) {
+        let mut buf = format!("; base {:08x}\n\n", align);
+        code(&mut buf, bytes, align);
+        return Ok(buf);
+    }
+
+    if bytes.len() < format::HEADER_LEN { return Err("Truncated vm64 header.") }
+    let count = be(&bytes[6..8]);
+    let mut buf = format!("; vm64 executable, entry {:08x}\n", be(&bytes[8..12]));
+
+    for i in 0..count {
+        let entry = bytes.get(format::HEADER_LEN + i * format::SECTION_LEN..format::HEADER_LEN + (i + 1) * format::SECTION_LEN).ok_or("Truncated vm64 section table.")?;
+        let name = String::from_utf8_lossy(&entry[..8]).trim_end_matches('\0').to_owned();
+        let (address, offset, size, flags) = (be(&entry[8..12]), be(&entry[12..16]), be(&entry[16..20]), be(&entry[20..24]) as u32);
+        let contents = bytes.get(offset..offset + size).ok_or("vm64 section runs past the end of the file.")?;
+
+        buf.push_str(&format!("\n; section {} at {:08x}\n", name, address));
+        if flags & format::SECTION_RELOC != 0 {
+            let (base, relocations) = reloc::parse(contents)?;
+            buf.push_str(&format!("; linked at {:08x}\n", base));
+            for (offset, width) in relocations { buf.push_str(&format!("; {:08x}  {} bytes\n", base + offset, width)) }
+        }
+        else if flags & format::SECTION_EXEC != 0 { code(&mut buf, contents, address) } else { data(&mut buf, contents, address) }
+    }
+
+    Ok(buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex_source, link};
+
+    #[test]
+    fn round_trip() {
+        let source = indoc::indoc! {"
+            mov r0 &3fea0700
+            loop:
+            mva r1 2 &2000
+            mov &10 r1 f2 8
+            jlg r0 r1 loop
+            grapcpy r0 &1000 1 2 3 4
+            out r1 &10
+            hlt
+            db &ff &ff
+        "};
+        let (_, bytes) = link(lex_source(source, "test.asm"), ".", 0x1000);
+        let text = disassemble(&bytes, 0x1000).unwrap();
+
+        assert!(text.contains("l_0000100a:\n"));
+        assert!(text.contains("    jlg r0 r1 l_0000100a"));
+        assert!(text.contains("    db &ff &ff"));
+
+        let source = text.lines().map(|l| l.split(";").next().unwrap().trim()).collect::<Vec<&str>>().join("\n");
+        assert_eq!(link(lex_source(&source, "test.asm"), ".", 0x1000).1, bytes);
+    }
+}
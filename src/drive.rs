@@ -0,0 +1,234 @@
+use std::{collections::HashMap, fs, path::Path};
+
+use crate::{assemble_with, error, Control, Line};
+
+/// Drive offset the VM boots from.
+pub const BOOT_OFFSET: usize = 0;
+
+/// Entries without an explicit offset are placed at the next multiple of this.
+pub const SECTOR: usize = 0x200;
+
+pub const TABLE_MAGIC: &[u8; 4] = b"VMDT";
+pub const TABLE_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EntryKind {
+    Boot,
+    Program,
+    Raw,
+}
+
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub name: String,
+    pub offset: usize,
+    pub load: usize,
+    pub bytes: Vec<u8>,
+}
+
+/// Reads a drive manifest. Every line is one entry, `;` starts a comment:
+///
+///     size    <bytes>
+///     table   <offset>
+///     boot    <input_folder>          [align <hex>]
+///     program <name> <input_folder>   [offset <hex>] [align <hex>]
+///     raw     <name> <file>           [offset <hex>]
+///
+/// Numbers are hex like `-align`. The boot program always lands at `BOOT_OFFSET`. Paths are
+/// relative to the manifest, programs are assembled with the target's `include` and `defines`.
+pub fn build_drive(manifest_path: &str, include: &[String], defines: &HashMap<String, String>) -> Vec<u8> {
+    let manifest = match fs::read_to_string(manifest_path) {
+        Ok(manifest) => manifest,
+        Err(_) => { error((Control::None, Vec::new(), String::new(), Vec::new(), manifest_path.to_owned(), 0), "Unable to read drive manifest."); return Vec::new() }
+    };
+
+    let root = Path::new(manifest_path).parent().unwrap_or(Path::new(""));
+    let path = |p: &str| root.join(p).display().to_string();
+    let assemble = |input: &str, align: usize| assemble_with(&path(input), include, defines, align, false, false).1;
+
+    let mut entries: Vec<(Entry, Line)> = Vec::new();
+    let mut table_offset = None;
+    let mut size = None;
+
+    let mut next: usize = 0;
+    for (i, text) in manifest.lines().enumerate() {
+        let text = text.split(";").next().unwrap().trim();
+        if text.is_empty() { continue }
+
+        let parts = text.split_whitespace().map(|s| s.to_owned()).collect::<Vec<String>>();
+        let line: Line = (Control::None, Vec::new(), parts[0].clone(), parts[1..].to_vec(), manifest_path.to_owned(), i + 1);
+
+        let hex = |n: &str| usize::from_str_radix(&n.replace("_", ""), 16);
+
+        // Positional arguments first, then `offset`/`align` pairs.
+        let positional = parts.iter().skip(1).take_while(|p| *p != "offset" && *p != "align").cloned().collect::<Vec<String>>();
+        let mut offset = None;
+        let mut align = 0;
+
+        let mut j = 1 + positional.len();
+        let mut invalid = false;
+        while j < parts.len() {
+            match (parts[j].as_str(), parts.get(j + 1).map(|n| hex(n))) {
+                ("offset", Some(Ok(n))) => offset = Some(n),
+                ("align", Some(Ok(n))) =>  align = n,
+
+                _ => { invalid = true; break }
+            }
+            j += 2;
+        }
+        if invalid { error(line, "Expected `offset <hex>` or `align <hex>`."); continue }
+
+        let entry = match (parts[0].to_lowercase().as_str(), positional.as_slice()) {
+            ("size", [n]) => {
+                match hex(n) {
+                    Ok(n) => size = Some((n, line)),
+                    Err(_) => error(line, "Invalid hex literal for drive size."),
+                }
+                continue
+            }
+            ("table", [n]) => {
+                match hex(n) {
+                    Ok(n) => table_offset = Some(n),
+                    Err(_) => error(line, "Invalid hex literal for table offset."),
+                }
+                continue
+            }
+            ("boot", [input]) => {
+                if offset.is_some_and(|o| o != BOOT_OFFSET) { error(line, "The boot program can't be moved."); continue }
+                offset = Some(BOOT_OFFSET);
+
+                Entry { kind: EntryKind::Boot, name: String::from("boot"), offset: BOOT_OFFSET, load: align, bytes: assemble(input, align) }
+            }
+            ("program", [name, input]) => {
+                Entry { kind: EntryKind::Program, name: name.clone(), offset: 0, load: align, bytes: assemble(input, align) }
+            }
+            ("raw", [name, file]) => {
+                match fs::read(path(file)) {
+                    Ok(bytes) => Entry { kind: EntryKind::Raw, name: name.clone(), offset: 0, load: 0, bytes },
+                    Err(_) => { error(line, "Couldn't open file."); continue }
+                }
+            }
+
+            ("size" | "table" | "boot" | "program" | "raw", _) => { error(line, "Invalid number of arguments."); continue }
+            _ => { error(line, "Unknown drive entry, expected size, table, boot, program or raw."); continue }
+        };
+
+        if entry.name.len() > 16 { error(line, "Entry names can be at most 16 bytes long."); continue }
+
+        let offset = offset.unwrap_or(next.div_ceil(SECTOR) * SECTOR);
+        next = next.max(offset + entry.bytes.len());
+
+        entries.push((Entry { offset, ..entry }, line));
+    }
+
+    let mut regions: Vec<(usize, usize, &Line)> = entries.iter().map(|(e, l)| (e.offset, e.offset + e.bytes.len(), l)).collect();
+    let table = table_offset.map(|offset| table(&entries.iter().map(|e| e.0.clone()).collect::<Vec<Entry>>(), offset));
+
+    let table_line: Line = (Control::None, Vec::new(), String::from("table"), Vec::new(), manifest_path.to_owned(), 0);
+    if let Some((offset, table)) = &table {
+        regions.push((*offset, offset + table.len(), &table_line));
+    }
+
+    regions.sort_by_key(|r| r.0);
+    for pair in regions.windows(2) {
+        if pair[1].0 < pair[0].1 {
+            error(pair[1].2.clone(), "Entry overlaps the previous entry on the drive.");
+        }
+    }
+
+    let end = regions.iter().map(|r| r.1).max().unwrap_or(0);
+    let size = match size {
+        Some((size, line)) if size < end => { error(line, &format!("Drive size 0x{:x} is too small, the entries end at 0x{:x}.", size, end)); return Vec::new() }
+        Some((size, _)) => size,
+        None => end,
+    };
+
+    let mut drive = vec![0; size];
+    for (entry, _) in entries.iter() {
+        drive[entry.offset..entry.offset + entry.bytes.len()].copy_from_slice(&entry.bytes);
+    }
+    if let Some((offset, table)) = table {
+        drive[offset..offset + table.len()].copy_from_slice(&table);
+    }
+
+    drive
+}
+
+/// Builds the directory table, all fields big endian:
+///
+///     magic "VMDT", version u16, entry count u16
+///     per entry: name [u8; 16], offset u32, size u32, load address u32, kind u32
+///
+/// where kind is 0 for the boot program, 1 for programs and 2 for raw files.
+pub fn table(entries: &[Entry], offset: usize) -> (usize, Vec<u8>) {
+    let mut table = TABLE_MAGIC.to_vec();
+    table.extend_from_slice(&TABLE_VERSION.to_be_bytes());
+    table.extend_from_slice(&(entries.len() as u16).to_be_bytes());
+
+    for entry in entries.iter() {
+        let mut name = [0u8; 16];
+        for (i, c) in entry.name.bytes().take(16).enumerate() { name[i] = c }
+
+        table.extend_from_slice(&name);
+        table.extend_from_slice(&(entry.offset as u32).to_be_bytes());
+        table.extend_from_slice(&(entry.bytes.len() as u32).to_be_bytes());
+        table.extend_from_slice(&(entry.load as u32).to_be_bytes());
+        table.extend_from_slice(&(match entry.kind { EntryKind::Boot => 0u32, EntryKind::Program => 1, EntryKind::Raw => 2 }).to_be_bytes());
+    }
+
+    (offset, table)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collect_errors, sim};
+
+    #[test]
+    fn manifest_layout() {
+        let dir = std::env::temp_dir().join(format!("vm64-drive-{}", std::process::id()));
+        fs::create_dir_all(dir.join("boot")).unwrap();
+        fs::create_dir_all(dir.join("lib")).unwrap();
+        fs::write(dir.join("boot/main.asm"), "mov r1 LIVES\njmp done\n").unwrap();
+        fs::write(dir.join("lib/done.asm"), "done:\nhlt\n").unwrap();
+        fs::write(dir.join("asset.bin"), [1, 2, 3]).unwrap();
+        fs::write(dir.join("drive.cfg"), indoc::indoc! {"
+            ; test drive
+            size    1000
+            table   400
+            boot    boot    align 00C00000
+            raw     asset asset.bin
+        "}).unwrap();
+
+        let include = [dir.join("lib").display().to_string()];
+        let defines = HashMap::from([(String::from("LIVES"), String::from("3"))]);
+        let (drive, errors) = collect_errors(|| build_drive(dir.join("drive.cfg").to_str().unwrap(), &include, &defines));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(errors.is_empty(), "{:?}", errors);
+        assert_eq!(drive.len(), 0x1000);
+        let mut machine = sim::Machine::new(&drive[..0x200], 0xC0_0000, 0xC0_8000);
+        assert_eq!((machine.run(100), machine.regs[1]), (sim::Stop::Halted, 3));
+        assert_eq!(&drive[0x200..0x203], &[1, 2, 3]);
+        assert_eq!(&drive[0x400..0x408], &[b'V', b'M', b'D', b'T', 0, 1, 0, 2]);
+        assert_eq!(&drive[0x408 + 0x10..0x408 + 0x20], &[0, 0, 0, 0, 0, 0, 0, 0x10, 0, 0xC0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn manifest_errors() {
+        let dir = std::env::temp_dir().join(format!("vm64-drive-errors-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("asset.bin"), [0; 0x300]).unwrap();
+        fs::write(dir.join("drive.cfg"), "size 200\nraw asset asset.bin\n").unwrap();
+
+        let (drive, errors) = collect_errors(|| build_drive(dir.join("drive.cfg").to_str().unwrap(), &[], &HashMap::new()));
+        let (_, missing) = collect_errors(|| build_drive(dir.join("missing.cfg").to_str().unwrap(), &[], &HashMap::new()));
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(drive.is_empty());
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![(1, "Drive size 0x200 is too small, the entries end at 0x300.")]);
+        assert_eq!(missing.iter().map(|e| e.1.as_str()).collect::<Vec<_>>(), vec!["Unable to read drive manifest."]);
+    }
+}
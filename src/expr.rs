@@ -0,0 +1,172 @@
+//! Integer expressions, for `#if` conditions and constant operands like `i*4+2`.
+//!
+//! Operators from loosest to tightest: `||`, `&&`, comparisons, `+ -`, `* / %`, then unary
+//! `! -` and parentheses. Numbers are decimal or hex with `&`/`0x`, comparisons give 1 or 0.
+
+use std::collections::HashMap;
+
+/// Splits an expression into numbers, names and operators.
+fn tokens(expr: &str) -> Result<Vec<String>, String> {
+    let chars = expr.chars().collect::<Vec<char>>();
+    let mut tokens = Vec::new();
+
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+
+        if c.is_whitespace() { i += 1; continue }
+
+        if i + 1 < chars.len() && ["==", "!=", "<=", ">=", "&&", "||"].contains(&format!("{}{}", c, chars[i + 1]).as_str()) {
+            i += 2;
+        }
+        else if c.is_ascii_alphanumeric() || c == '_' || c == '&' {
+            i += 1;
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '_') { i += 1 }
+        }
+        else if "()!+-*/%<>".contains(c) {
+            i += 1;
+        }
+        else {
+            return Err(format!("Unexpected `{}` in expression.", c));
+        }
+
+        tokens.push(chars[start..i].iter().collect());
+    }
+
+    Ok(tokens)
+}
+
+fn number(text: &str) -> Option<i64> {
+    let text = text.replace("_", "");
+    match text.strip_prefix("&").or(text.strip_prefix("0x")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok().map(|n| n as i64),
+        None if text == "true" => Some(1),
+        None if text == "false" => Some(0),
+        None => text.parse::<u64>().ok().map(|n| n as i64),
+    }
+}
+
+/// Precedence climbing over `tokens`, lowest binding first.
+struct Parser<'a> {
+    tokens: Vec<String>,
+    at: usize,
+    defines: &'a HashMap<String, String>,
+    /// Names that aren't defined are errors instead of 0.
+    strict: bool,
+}
+
+const LEVELS: &[&[&str]] = &[&["||"], &["&&"], &["==", "!=", "<", ">", "<=", ">="], &["+", "-"], &["*", "/", "%"]];
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.at).map(|t| t.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        self.at += 1;
+        self.tokens.get(self.at - 1).cloned().ok_or_else(|| String::from("Incomplete expression."))
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == LEVELS.len() { return self.unary() }
+
+        let mut value = self.binary(level + 1)?;
+        while let Some(op) = self.peek().filter(|t| LEVELS[level].contains(t)).map(|t| t.to_owned()) {
+            self.at += 1;
+            let rhs = self.binary(level + 1)?;
+
+            value = match op.as_str() {
+                "||" => (value != 0 || rhs != 0) as i64,
+                "&&" => (value != 0 && rhs != 0) as i64,
+                "==" => (value == rhs) as i64,
+                "!=" => (value != rhs) as i64,
+                "<" =>  (value < rhs) as i64,
+                ">" =>  (value > rhs) as i64,
+                "<=" => (value <= rhs) as i64,
+                ">=" => (value >= rhs) as i64,
+                "+" =>  value.wrapping_add(rhs),
+                "-" =>  value.wrapping_sub(rhs),
+                "*" =>  value.wrapping_mul(rhs),
+                "/" =>  value.checked_div(rhs).ok_or("Division by zero in expression.")?,
+
+                _ =>    value.checked_rem(rhs).ok_or("Division by zero in expression.")?,
+            };
+        }
+
+        Ok(value)
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        let token = self.next()?;
+
+        match token.as_str() {
+            "!" => Ok((self.unary()? == 0) as i64),
+            "-" => Ok(self.unary()?.wrapping_neg()),
+            "(" => {
+                let value = self.binary(0)?;
+                if self.next()? != ")" { return Err(String::from("Expected `)`.")) }
+                Ok(value)
+            }
+            "defined" if !self.strict => {
+                let parens = self.peek() == Some("(");
+                if parens { self.at += 1 }
+                let name = self.next()?;
+                if parens && self.next()? != ")" { return Err(String::from("Expected `)`.")) }
+                Ok(self.defines.contains_key(&name) as i64)
+            }
+
+            _ => match (number(&token), self.defines.get(&token)) {
+                (Some(n), _) =>     Ok(n),
+                (None, Some(v)) =>  number(v.trim()).ok_or_else(|| format!("`{}` is `{}`, which isn't a number.", token, v)),
+                (None, None) if !self.strict && token.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => Ok(0),
+                (None, None) =>     Err(format!("Unexpected `{}` in expression.", token)),
+            },
+        }
+    }
+}
+
+fn parse(expr: &str, defines: &HashMap<String, String>, strict: bool) -> Result<i64, String> {
+    let mut parser = Parser { tokens: tokens(expr)?, at: 0, defines, strict };
+    let value = parser.binary(0)?;
+
+    match parser.peek() {
+        Some(token) => Err(format!("Unexpected `{}` in expression.", token)),
+        None => Ok(value),
+    }
+}
+
+/// Evaluates with names looked up in `defines`, undefined names are 0 and `defined(NAME)`
+/// tells whether a name is defined.
+pub fn eval(expr: &str, defines: &HashMap<String, String>) -> Result<i64, String> {
+    parse(expr, defines, false)
+}
+
+/// Evaluates an expression of numbers only.
+pub fn constant(expr: &str) -> Result<i64, String> {
+    parse(expr, &HashMap::new(), true)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expressions() {
+        let defines = HashMap::from([(String::from("LIVES"), String::from("3")), (String::from("DEBUG"), String::from("true"))]);
+
+        assert_eq!(eval("LIVES > 2 && DEBUG", &defines), Ok(1));
+        assert_eq!(eval("(LIVES + 1) * &10 == 64 || MISSING", &defines), Ok(1));
+        assert_eq!(eval("!defined(DEMO) && defined LIVES", &defines), Ok(1));
+        assert_eq!(eval("-LIVES % 2", &defines), Ok(-1));
+        assert!(eval("LIVES / 0", &defines).is_err());
+        assert!(eval("LIVES >", &defines).is_err());
+    }
+
+    #[test]
+    fn constants() {
+        assert_eq!(constant("(3+1)*&10-2"), Ok(62));
+        assert!(constant("label+4").unwrap_err().contains("label"));
+    }
+}
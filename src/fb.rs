@@ -0,0 +1,137 @@
+use image::{Rgb, RgbImage};
+
+use crate::sim::Memory;
+
+pub const DEFAULT_BASE: usize = 0x3FEA_0700;
+pub const DEFAULT_WIDTH: usize = 800;
+pub const DEFAULT_HEIGHT: usize = 600;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PixelFormat {
+    Rgb8,
+    Rgba8,
+    Gray8,
+}
+
+impl PixelFormat {
+    pub fn bytes(&self) -> usize {
+        match self {
+            PixelFormat::Rgb8 =>    3,
+            PixelFormat::Rgba8 =>   4,
+            PixelFormat::Gray8 =>   1,
+        }
+    }
+
+    /// Converts an RGB pixel, as stored by `#image`, to this format.
+    fn encode(&self, rgb: &[u8]) -> Vec<u8> {
+        match self {
+            PixelFormat::Rgb8 =>    rgb.to_vec(),
+            PixelFormat::Rgba8 =>   vec![rgb[0], rgb[1], rgb[2], 0xFF],
+            PixelFormat::Gray8 =>   vec![((rgb[0] as u16 * 77 + rgb[1] as u16 * 150 + rgb[2] as u16 * 29) >> 8) as u8],
+        }
+    }
+
+    fn decode(&self, pixel: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Gray8 =>   [pixel[0]; 3],
+
+            _ =>                    [pixel[0], pixel[1], pixel[2]],
+        }
+    }
+}
+
+/// Memory mapped framebuffer, `width * height` pixels in row major order starting at `base`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Framebuffer {
+    pub base: usize,
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+}
+
+impl Default for Framebuffer {
+    fn default() -> Framebuffer {
+        Framebuffer { base: DEFAULT_BASE, width: DEFAULT_WIDTH, height: DEFAULT_HEIGHT, format: PixelFormat::Rgb8 }
+    }
+}
+
+impl Framebuffer {
+    /// Parses `<base>[:<width>x<height>[:<format>]]`, the base in hex.
+    pub fn parse(spec: &str) -> Result<Framebuffer, &'static str> {
+        let mut fb = Framebuffer::default();
+        let mut parts = spec.split(":");
+
+        if let Some(base) = parts.next().filter(|b| !b.is_empty()) {
+            fb.base = usize::from_str_radix(&base.replace("_", ""), 16).map_err(|_| "Invalid hex literal for framebuffer base.")?;
+        }
+        if let Some(size) = parts.next() {
+            let (w, h) = size.split_once("x").ok_or("Expected <width>x<height> for framebuffer size.")?;
+            fb.width = w.parse().map_err(|_| "Invalid framebuffer width.")?;
+            fb.height = h.parse().map_err(|_| "Invalid framebuffer height.")?;
+        }
+        if let Some(format) = parts.next() {
+            fb.format = match format.to_lowercase().as_str() {
+                "rgb8" =>   PixelFormat::Rgb8,
+                "rgba8" =>  PixelFormat::Rgba8,
+                "gray8" =>  PixelFormat::Gray8,
+
+                _ => return Err("Unknown pixel format, expected rgb8, rgba8 or gray8."),
+            };
+        }
+
+        Ok(fb)
+    }
+
+    /// Executes `grapcpy`: copies a `w * h` RGB image from `src` to pixel `x, y` of the
+    /// framebuffer layout starting at `dst`. Pixels outside the framebuffer are clipped.
+    #[allow(clippy::too_many_arguments)]
+    pub fn blit(&self, memory: &mut Memory, src: usize, dst: usize, x: usize, y: usize, w: usize, h: usize) {
+        let bpp = self.format.bytes();
+
+        for row in 0..h {
+            if y + row >= self.height { break }
+
+            let pixels = memory.read(src + row * w * 3, w.min(self.width.saturating_sub(x)) * 3);
+            let line = pixels.chunks(3).flat_map(|p| self.format.encode(p)).collect::<Vec<u8>>();
+
+            memory.write(dst + ((y + row) * self.width + x) * bpp, &line);
+        }
+    }
+
+    pub fn snapshot(&self, memory: &Memory) -> RgbImage {
+        let bpp = self.format.bytes();
+        let bytes = memory.read(self.base, self.width * self.height * bpp);
+
+        RgbImage::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let i = (y as usize * self.width + x as usize) * bpp;
+            Rgb(self.format.decode(&bytes[i..i + bpp]))
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_spec() {
+        assert_eq!(Framebuffer::parse("1000:4x2:rgba8").unwrap(), Framebuffer { base: 0x1000, width: 4, height: 2, format: PixelFormat::Rgba8 });
+        assert_eq!(Framebuffer::parse("").unwrap(), Framebuffer::default());
+        assert!(Framebuffer::parse("1000:4").is_err());
+    }
+
+    #[test]
+    fn blit_and_snapshot() {
+        let fb = Framebuffer { base: 0x1000, width: 4, height: 2, format: PixelFormat::Rgb8 };
+        let mut memory = Memory::default();
+        memory.write(0x100, &[1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+
+        fb.blit(&mut memory, 0x100, fb.base, 3, 0, 2, 2);
+
+        let image = fb.snapshot(&memory);
+        assert_eq!(image.get_pixel(3, 0), &Rgb([1, 2, 3]));
+        assert_eq!(image.get_pixel(3, 1), &Rgb([7, 8, 9]));
+        assert_eq!(image.get_pixel(0, 1), &Rgb([0, 0, 0]));
+    }
+}
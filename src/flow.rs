@@ -0,0 +1,280 @@
+//! Structured control flow, lowered while lexing to `jpe`/`jne`/`jlg` and hidden labels.
+//!
+//!     #if r0 == r1        #while r1 < r2      #loop r3 10
+//!     ...                 ...                 ...
+//!     #else               #endwhile           #endloop
+//!     ...
+//!     #endif
+//!
+//! Conditions compare two registers of the same kind with `==`, `!=`, `<`, `>`, `<=` or `>=`.
+//! `#loop` runs its body `count` times, with the register counting up from 0, like `#while` with
+//! an increment at the end. The count is a register, a literal or a constant expression, the
+//! last two are loaded into `rfd` before every comparison.
+//! `break` and `continue` apply to the innermost `#while` or `#loop`.
+
+use std::collections::HashMap;
+
+use crate::{generate, resolve_arg, Arg, Control, Line};
+
+/// Prefix of generated labels, they're left out of symbol tables and source labels can't use it.
+pub const HIDDEN: &str = "@";
+
+/// Holds a literal `#loop` count for each comparison, anything in it is lost inside such a loop.
+pub const COUNT_REG: &str = "rfd";
+
+#[derive(Debug, PartialEq)]
+enum Block {
+    If,
+    Else,
+    While,
+    Loop,
+}
+
+/// Open blocks of one file, with the line that opened each and the name its labels start with.
+#[derive(Debug, Default)]
+pub struct Flow {
+    blocks: Vec<(Block, Line, String)>,
+    /// Blocks opened so far per source line, lines repeat inside `#rep` and `#for`.
+    opened: HashMap<usize, usize>,
+}
+
+fn label(base: &str, part: &str) -> String {
+    format!("{}.{}", base, part)
+}
+
+fn define(at: &Line, name: &str) -> Line {
+    (Control::Label, Vec::new(), format!("{}:", name), Vec::new(), at.4.clone(), at.5)
+}
+
+fn negate(op: &str) -> &str {
+    match op {
+        "==" => "!=",
+        "!=" => "==",
+        "<" =>  ">=",
+        ">=" => "<",
+        ">" =>  "<=",
+        "<=" => ">",
+
+        _ => op,
+    }
+}
+
+/// Jumps to `target` when `a op b` holds. `<=` and `>=` have no instruction of their own,
+/// they jump over a `jmp` when the opposite holds.
+fn jump_if(line: &Line, base: &str, a: &str, op: &str, b: &str, target: &str) -> Result<Vec<Line>, &'static str> {
+    match op {
+        "==" => Ok(vec![generate(line, "jpe", &[a, b, target])?]),
+        "!=" => Ok(vec![generate(line, "jne", &[a, b, target])?]),
+        "<" =>  Ok(vec![generate(line, "jlg", &[a, b, target])?]),
+        ">" =>  Ok(vec![generate(line, "jlg", &[b, a, target])?]),
+        "<=" | ">=" => {
+            let skip = label(base, "skip");
+            let (x, y) = if op == "<=" { (b, a) } else { (a, b) };
+
+            Ok(vec![generate(line, "jlg", &[x, y, &skip])?, generate(line, "jmp", &[target])?, define(line, &skip)])
+        }
+
+        _ => Err("Expected a comparison, one of == != < > <= >=."),
+    }
+}
+
+/// Jumps to `target` unless the condition of `line` holds.
+fn jump_unless(line: &Line, base: &str, target: &str) -> Result<Vec<Line>, &'static str> {
+    let [a, op, b] = line.3.as_slice() else { return Err("Expected a condition like `r0 < r1`.") };
+    for reg in [a, b] {
+        if !matches!(resolve_arg(reg.clone()), Ok(Arg::Ureg(_) | Arg::Freg(_))) { return Err("Expected a condition like `r0 < r1`.") }
+    }
+
+    jump_if(line, base, a, negate(op), b, target)
+}
+
+impl Flow {
+    /// Lowers a block directive, `break` or `continue`, `None` when `line` is none of them.
+    pub fn lower(&mut self, line: &Line) -> Option<Result<Vec<Line>, &'static str>> {
+        let keyword = line.2.to_lowercase();
+        if !["#if", "#else", "#endif", "#while", "#endwhile", "#loop", "#endloop", "break", "continue"].contains(&keyword.as_str()) {
+            return None;
+        }
+
+        Some(self.lower_keyword(&keyword, line))
+    }
+
+    /// Opens a block at `line`, its hidden labels are named after the line so they're unique
+    /// across files.
+    fn open(&mut self, block: Block, line: &Line) -> String {
+        let count = self.opened.entry(line.5).or_default();
+        let base = match *count {
+            0 => format!("{}{}:{}", HIDDEN, line.4, line.5),
+            n => format!("{}{}:{}~{}", HIDDEN, line.4, line.5, n),
+        };
+
+        *count += 1;
+        self.blocks.push((block, line.clone(), base.clone()));
+        base
+    }
+
+    fn lower_keyword(&mut self, keyword: &str, line: &Line) -> Result<Vec<Line>, &'static str> {
+        let top = self.blocks.last().map(|(block, _, base)| (block, base.clone()));
+
+        let lines = match (keyword, top) {
+            // Blocks are opened before their condition is checked, so one bad condition
+            // doesn't also make its closing directive an error.
+            ("#if", _) => {
+                let base = self.open(Block::If, line);
+                jump_unless(line, &base, &label(&base, "else"))?
+            }
+            ("#else", Some((Block::If, base))) => {
+                self.blocks.last_mut().unwrap().0 = Block::Else;
+                vec![generate(line, "jmp", &[&label(&base, "end")])?, define(line, &label(&base, "else"))]
+            }
+            ("#endif", Some((Block::If, base))) => {
+                self.blocks.pop();
+                vec![define(line, &label(&base, "else"))]
+            }
+            ("#endif", Some((Block::Else, base))) => {
+                self.blocks.pop();
+                vec![define(line, &label(&base, "end"))]
+            }
+
+            ("#while", _) => {
+                let base = self.open(Block::While, line);
+                let mut lines = vec![define(line, &label(&base, "top"))];
+                lines.extend(jump_unless(line, &base, &label(&base, "end"))?);
+                lines
+            }
+            ("#loop", _) => {
+                let base = self.open(Block::Loop, line);
+                let [counter, count] = line.3.as_slice() else { return Err("Expected `#loop <register> <count>`.") };
+                if !matches!(resolve_arg(counter.clone()), Ok(Arg::Ureg(_))) { return Err("Invalid argument, expected register.") }
+
+                let mut lines = vec![generate(line, "mov", &[counter, "0"])?, define(line, &label(&base, "top"))];
+                let count = match resolve_arg(count.clone()) {
+                    Ok(Arg::Ureg(_)) => count.as_str(),
+                    Ok(Arg::Liter(_)) => { lines.push(generate(line, "mov", &[COUNT_REG, count])?); COUNT_REG }
+
+                    _ => return Err("Invalid argument, expected literal or register."),
+                };
+                lines.extend(jump_if(line, &base, counter, ">=", count, &label(&base, "end"))?);
+                lines
+            }
+            ("#endwhile", Some((Block::While, base))) => {
+                self.blocks.pop();
+                vec![generate(line, "jmp", &[&label(&base, "top")])?, define(line, &label(&base, "end"))]
+            }
+            ("#endloop", Some((Block::Loop, base))) => {
+                let (_, opening, _) = self.blocks.pop().unwrap();
+                vec![
+                    define(line, &label(&base, "next")),
+                    generate(line, "inc", &[&opening.3[0]])?,
+                    generate(line, "jmp", &[&label(&base, "top")])?,
+                    define(line, &label(&base, "end")),
+                ]
+            }
+
+            ("break" | "continue", _) => {
+                if !line.3.is_empty() { return Err("Invalid number of arguments.") }
+                let (block, _, base) = self.blocks.iter().rev().find(|(b, _, _)| *b == Block::While || *b == Block::Loop)
+                    .ok_or("Only allowed inside `#while` or `#loop`.")?;
+
+                // `continue` in a `#loop` goes through the increment.
+                let target = match (keyword, block) {
+                    ("break", _) =>         "end",
+                    (_, Block::Loop) =>     "next",
+                    _ =>                    "top",
+                };
+                vec![generate(line, "jmp", &[&label(base, target)])?]
+            }
+
+            ("#else", _) =>     return Err("`#else` without `#if`."),
+            ("#endif", _) =>    return Err("`#endif` without `#if`."),
+            ("#endwhile", _) => return Err("`#endwhile` without `#while`."),
+
+            _ =>                return Err("`#endloop` without `#loop`."),
+        };
+
+        Ok(lines)
+    }
+
+    /// Blocks left open at the end of the file, with an error for each.
+    pub fn unclosed(self) -> Vec<(Line, &'static str)> {
+        self.blocks.into_iter().map(|(block, opening, _)| {
+            let error = match block {
+                Block::If | Block::Else =>  "`#if` without `#endif`.",
+                Block::While =>             "`#while` without `#endwhile`.",
+                Block::Loop =>              "`#loop` without `#endloop`.",
+            };
+            (opening, error)
+        }).collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{collect_errors, lex_source, link, sim};
+
+    fn run(source: &str) -> sim::Machine {
+        let (_, bytes) = link(lex_source(source, "test.asm"), ".", 0x1000);
+        let mut machine = sim::Machine::new(&bytes, 0x1000, 0x8000);
+        assert_eq!(machine.run(10_000), sim::Stop::Halted);
+        machine
+    }
+
+    #[test]
+    fn lowered_blocks_run() {
+        let machine = run(indoc::indoc! {"
+            mov r1 0
+            mov r2 10
+            mov r9 1
+            #while r1 < r2
+                add r1 r1 r9
+                #if r1 >= r2
+                    break
+                #else
+                    inc r4
+                #endif
+            #endwhile
+            mov r6 5
+            #loop r3 r6
+                #if r3 == r0
+                    continue
+                #endif
+                inc r5
+            #endloop
+            hlt
+        "});
+        assert_eq!((machine.regs[1], machine.regs[3], machine.regs[4], machine.regs[5]), (10, 5, 9, 4));
+    }
+
+    #[test]
+    fn literal_counts() {
+        let machine = run(indoc::indoc! {"
+            #loop r3 10
+                inc r5
+            #endloop
+            #loop r1 2*3
+                #loop r2 4
+                    inc r6
+                #endloop
+            #endloop
+            hlt
+        "});
+        assert_eq!((machine.regs[3], machine.regs[5], machine.regs[6]), (10, 10, 24));
+    }
+
+    #[test]
+    fn errors_point_at_the_statement() {
+        let (_, errors) = collect_errors(|| lex_source("#if r0 == f1\n#endif\nbreak\n#while r0 ~ r1\n#endloop\n#loop r1 f3\n", "test.asm"));
+        let errors = errors.iter().map(|e| (e.0.2.as_str(), e.0.5, e.1.as_str())).collect::<Vec<_>>();
+
+        assert_eq!(errors, vec![
+            ("#if", 1, "Mismatched register types."),
+            ("break", 3, "Only allowed inside `#while` or `#loop`."),
+            ("#while", 4, "Expected a comparison, one of == != < > <= >=."),
+            ("#endloop", 5, "`#endloop` without `#loop`."),
+            ("#loop", 6, "Invalid argument, expected literal or register."),
+            ("#while", 4, "`#while` without `#endwhile`."),
+            ("#loop", 6, "`#loop` without `#endloop`."),
+        ]);
+    }
+}
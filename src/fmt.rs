@@ -0,0 +1,180 @@
+use crate::tokenize;
+
+/// Indentation of instructions. Labels and directives stay in the first column.
+pub const INDENT: usize = 4;
+
+/// Values per line before a `db` is wrapped with `\` continuations.
+pub const DB_WRAP: usize = 16;
+
+enum Row {
+    Blank,
+    Comment(bool, String),
+    Code(Vec<String>, Option<String>),
+}
+
+/// Writes literals and registers one way: hex lowercase with `&`, decimal without leading
+/// zeros, no `_` separators. Anything the assembler wouldn't read as a number is kept as is.
+fn normalise(token: &str) -> String {
+    let plain = token.replace("_", "");
+
+    if let Some(hex) = plain.strip_prefix("&") {
+        if u64::from_str_radix(hex, 16).is_ok() { return format!("&{}", hex.to_lowercase()) }
+    }
+    else if let Ok(n) = plain.parse::<u64>() {
+        return n.to_string();
+    }
+    else if (plain.starts_with("r") || plain.starts_with("f")) && plain.len() < 4 && u8::from_str_radix(&plain[1..], 16).is_ok() {
+        return plain.to_lowercase();
+    }
+
+    token.to_owned()
+}
+
+/// Splits the source like the lexer does: `\` continuations are joined first, then `;` starts
+/// a comment up to the end of the line.
+fn rows(source: &str) -> Vec<Row> {
+    source.replace("\r", "").replace("\\\n", " ").lines().map(|line| {
+        let (code, comment) = match line.split_once(";") {
+            Some((code, comment)) => (code, Some(comment.trim_end().to_owned())),
+            None => (line, None),
+        };
+        // Directives with a `{ ... }` body, like `#struct`, are kept as written.
+        let tokens = match code.trim() {
+            code if code.starts_with("#") && code.contains("{") => vec![code.to_owned()],
+
+            _ => tokenize(code),
+        };
+
+        match (tokens.is_empty(), comment) {
+            (true, Some(comment)) =>    Row::Comment(line.starts_with(char::is_whitespace), comment),
+            (true, None) =>             Row::Blank,
+            (false, comment) =>         Row::Code(tokens, comment),
+        }
+    }).collect()
+}
+
+fn is_instruction(tokens: &[String]) -> bool {
+    !tokens[0].ends_with(":") && !tokens[0].starts_with("#")
+}
+
+/// Formats one block of lines without blank lines between them. Mnemonics and operands are
+/// padded to the widest one in the block and trailing comments start in a shared column.
+fn block(rows: &[Row], buf: &mut String) {
+    let mut mnemonic = 0;
+    let mut operands: Vec<usize> = Vec::new();
+    for row in rows.iter() {
+        if let Row::Code(tokens, _) = row {
+            if !is_instruction(tokens) { continue }
+            mnemonic = mnemonic.max(tokens[0].len());
+
+            if tokens[0].eq_ignore_ascii_case("db") { continue }
+            for (i, arg) in tokens[1..].iter().enumerate() {
+                if operands.len() <= i { operands.push(0) }
+                operands[i] = operands[i].max(normalise(arg).len());
+            }
+        }
+    }
+
+    // Every row as its lines of code plus the comment, which goes on the last line.
+    let mut lines: Vec<(Vec<String>, Option<&String>)> = Vec::new();
+    for row in rows.iter() {
+        match row {
+            Row::Code(tokens, comment) if is_instruction(tokens) => {
+                let name = tokens[0].to_lowercase();
+                let args = tokens[1..].iter().map(|a| normalise(a)).collect::<Vec<String>>();
+                let head = format!("{}{:<w$} ", " ".repeat(INDENT), name, w = mnemonic);
+
+                let code = if name == "db" {
+                    let chunks = args.chunks(DB_WRAP).map(|c| c.join(" ")).collect::<Vec<String>>();
+                    let last = chunks.len().saturating_sub(1);
+                    chunks.iter().enumerate().map(|(i, chunk)| {
+                        let start = if i == 0 { head.clone() } else { " ".repeat(head.len()) };
+                        format!("{}{}{}", start, chunk, if i < last { " \\" } else { "" })
+                    }).collect()
+                }
+                else {
+                    let args = args.iter().enumerate().map(|(i, a)| format!("{:<w$}", a, w = operands[i])).collect::<Vec<String>>();
+                    vec![format!("{}{}", head, args.join(" "))]
+                };
+
+                lines.push((code.into_iter().map(|l| l.trim_end().to_owned()).collect(), comment.as_ref()));
+            }
+            Row::Code(tokens, comment) =>   lines.push((vec![tokens.join(" ")], comment.as_ref())),
+            Row::Comment(indent, comment) => lines.push((vec![format!("{};{}", if *indent { " ".repeat(INDENT) } else { String::new() }, comment)], None)),
+
+            Row::Blank => {}
+        }
+    }
+
+    let column = rows.iter().zip(lines.iter())
+        .filter(|(row, line)| matches!(row, Row::Code(..)) && line.1.is_some())
+        .map(|(_, line)| line.0.last().unwrap().len())
+        .max().unwrap_or(0);
+
+    for (code, comment) in lines.iter() {
+        for (i, line) in code.iter().enumerate() {
+            match comment {
+                Some(comment) if i + 1 == code.len() => buf.push_str(&format!("{:<w$} ;{}\n", line, comment, w = column)),
+
+                _ => buf.push_str(&format!("{}\n", line)),
+            }
+        }
+    }
+}
+
+/// Formats a source file. Formatting is stable and never changes the assembled bytes.
+pub fn format(source: &str) -> String {
+    let rows = rows(source);
+
+    let mut buf = String::new();
+    for group in rows.split(|r| matches!(r, Row::Blank)).filter(|g| !g.is_empty()) {
+        if !buf.is_empty() { buf.push('\n') }
+        block(group, &mut buf);
+    }
+
+    buf
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex_source, link};
+
+    const SOURCE: &str = indoc::indoc! {"
+        ; pattern
+        start:   ; entry
+          mov r0 &3FEA_0700 ; framebuffer
+        or  r0 r0 rA
+            mov rB 0x10
+
+
+        db 1 2 3 4 5 6 7 8 \\
+        9 10 11 12 13 14 15 16 17 18
+        #bytes font font.bin
+    "};
+
+    #[test]
+    fn aligns_and_normalises() {
+        assert_eq!(format(SOURCE), indoc::indoc! {"
+            ; pattern
+            start:               ; entry
+                mov r0 &3fea0700 ; framebuffer
+                or  r0 r0        ra
+                mov rb &10
+
+                db 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 \\
+                   17 18
+            #bytes font font.bin
+        "});
+    }
+
+    #[test]
+    fn stable_and_same_bytes() {
+        let formatted = format(SOURCE);
+        assert_eq!(format(&formatted), formatted);
+
+        let bytes = |source: &str| link(lex_source(&source.replace("#bytes font font.bin\n", ""), "test.asm"), ".", 0).1;
+        assert_eq!(bytes(&formatted), bytes(SOURCE));
+    }
+}
@@ -0,0 +1,217 @@
+use crate::map::{Symbol, SymbolKind};
+
+pub const MAGIC: &[u8; 4] = b"VM64";
+pub const VERSION: u16 = 1;
+
+pub const HEADER_LEN: usize = 0x10;
+pub const SECTION_LEN: usize = 0x18;
+
+pub const SECTION_EXEC: u32 = 1;
+pub const SECTION_DATA: u32 = 2;
+/// Not loaded, holds the relocation table of `reloc`.
+pub const SECTION_RELOC: u32 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Format {
+    Bin,
+    Ihex,
+    Srec,
+    Vm64,
+}
+
+impl Format {
+    pub fn parse(name: &str) -> Result<Format, &'static str> {
+        match name.to_lowercase().as_str() {
+            "bin" =>            Ok(Format::Bin),
+            "ihex" | "hex" =>   Ok(Format::Ihex),
+            "srec" =>           Ok(Format::Srec),
+            "vm64" | "exe" =>   Ok(Format::Vm64),
+
+            _ => Err("Unknown output format, expected bin, ihex, srec or vm64."),
+        }
+    }
+}
+
+/// Encodes the flat program bytes, which are loaded at `align`, in the requested format. Only
+/// vm64 can embed a relocation table.
+pub fn encode(format: &Format, bytes: &[u8], symbols: &[Symbol], align: usize, relocations: Option<&[u8]>) -> Vec<u8> {
+    match format {
+        Format::Bin =>  bytes.to_vec(),
+        Format::Ihex => ihex(bytes, align).into_bytes(),
+        Format::Srec => srec(bytes, align).into_bytes(),
+        Format::Vm64 => vm64(bytes, symbols, align, relocations),
+    }
+}
+
+fn ihex_record(kind: u8, address: u16, data: &[u8]) -> String {
+    let mut record = vec![data.len() as u8];
+    record.extend_from_slice(&address.to_be_bytes());
+    record.push(kind);
+    record.extend_from_slice(data);
+
+    let checksum = record.iter().fold(0u8, |sum, n| sum.wrapping_add(*n)).wrapping_neg();
+    record.push(checksum);
+
+    format!(":{}\n", record.iter().map(|n| format!("{:02X}", n)).collect::<String>())
+}
+
+/// Intel HEX with extended linear address records, so the full 32 bit load address is kept.
+pub fn ihex(bytes: &[u8], align: usize) -> String {
+    let mut buf = String::new();
+    let mut upper = None;
+
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        let address = (align + i * 16) as u32;
+
+        // Records can't cross a 64k boundary, split the chunk if it does.
+        let split = (0x10000 - (address & 0xFFFF) as usize).min(chunk.len());
+        for (address, data) in [(address, &chunk[..split]), (address.wrapping_add(split as u32), &chunk[split..])] {
+            if data.is_empty() { continue }
+
+            if upper != Some(address >> 16) {
+                upper = Some(address >> 16);
+                buf.push_str(&ihex_record(0x04, 0, &((address >> 16) as u16).to_be_bytes()));
+            }
+            buf.push_str(&ihex_record(0x00, address as u16, data));
+        }
+    }
+
+    buf.push_str(&ihex_record(0x05, 0, &(align as u32).to_be_bytes()));
+    buf.push_str(&ihex_record(0x01, 0, &[]));
+    buf
+}
+
+fn srec_record(kind: u8, address: &[u8], data: &[u8]) -> String {
+    let mut record = vec![(address.len() + data.len() + 1) as u8];
+    record.extend_from_slice(address);
+    record.extend_from_slice(data);
+
+    let checksum = !record.iter().fold(0u8, |sum, n| sum.wrapping_add(*n));
+    record.push(checksum);
+
+    format!("S{}{}\n", kind, record.iter().map(|n| format!("{:02X}", n)).collect::<String>())
+}
+
+/// Motorola S-record with 32 bit addresses (S3 data, S7 entry).
+pub fn srec(bytes: &[u8], align: usize) -> String {
+    let mut buf = srec_record(0, &[0, 0], b"vm64");
+
+    let chunks = bytes.chunks(16);
+    let count = chunks.len();
+    for (i, chunk) in chunks.enumerate() {
+        buf.push_str(&srec_record(3, &((align + i * 16) as u32).to_be_bytes(), chunk));
+    }
+
+    if count <= 0xFFFF {
+        buf.push_str(&srec_record(5, &(count as u16).to_be_bytes(), &[]));
+    }
+    buf.push_str(&srec_record(7, &(align as u32).to_be_bytes(), &[]));
+    buf
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for n in bytes {
+        crc ^= *n as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// The headered vm64 executable, all fields big endian:
+///
+///     0x00  magic "VM64"
+///     0x04  version        u16
+///     0x06  section count  u16
+///     0x08  entry point    u32
+///     0x0C  CRC-32 of everything after the header
+///     0x10  section table, per section:
+///           name [u8; 8], load address u32, file offset u32, size u32, flags u32
+///
+/// followed by the section contents. A relocation table is the last section, `.reloc` at
+/// address 0.
+pub fn vm64(bytes: &[u8], symbols: &[Symbol], align: usize, relocations: Option<&[u8]>) -> Vec<u8> {
+    let mut sections = symbols.iter().filter(|s| s.kind == SymbolKind::Section && s.size > 0)
+        .map(|s| (s.name.as_str(), s.address, &bytes[s.address - align..s.address - align + s.size], if s.name == ".text" { SECTION_EXEC } else { SECTION_DATA }))
+        .collect::<Vec<(&str, usize, &[u8], u32)>>();
+    if let Some(relocations) = relocations {
+        sections.push((".reloc", 0, relocations, SECTION_RELOC));
+    }
+
+    let mut table = Vec::new();
+    let mut payload = Vec::new();
+
+    let mut offset = HEADER_LEN + sections.len() * SECTION_LEN;
+    for (section, address, contents, flags) in sections.iter() {
+        let mut name = [0u8; 8];
+        for (i, c) in section.bytes().take(8).enumerate() { name[i] = c }
+
+        table.extend_from_slice(&name);
+        table.extend_from_slice(&(*address as u32).to_be_bytes());
+        table.extend_from_slice(&(offset as u32).to_be_bytes());
+        table.extend_from_slice(&(contents.len() as u32).to_be_bytes());
+        table.extend_from_slice(&flags.to_be_bytes());
+
+        payload.extend_from_slice(contents);
+        offset += contents.len();
+    }
+
+    let mut body = table;
+    body.append(&mut payload);
+
+    let mut exe = MAGIC.to_vec();
+    exe.extend_from_slice(&VERSION.to_be_bytes());
+    exe.extend_from_slice(&(sections.len() as u16).to_be_bytes());
+    exe.extend_from_slice(&(align as u32).to_be_bytes());
+    exe.extend_from_slice(&crc32(&body).to_be_bytes());
+    exe.append(&mut body);
+    exe
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ihex_records() {
+        assert_eq!(ihex(&[0x00, 0x70], 0x00C0_0000), concat!(
+            ":0200000400C03A\n",
+            ":0200000000708E\n",
+            ":0400000500C0000037\n",
+            ":00000001FF\n",
+        ));
+    }
+
+    #[test]
+    fn srec_records() {
+        assert_eq!(srec(&[0x00, 0x70], 0x00C0_0000), concat!(
+            "S0070000766D3634AB\n",
+            "S30700C000000070C8\n",
+            "S5030001FB\n",
+            "S70500C000003A\n",
+        ));
+    }
+
+    #[test]
+    fn vm64_header() {
+        let symbols = vec![
+            Symbol { kind: SymbolKind::Section, name: String::from(".text"), address: 0x100, size: 2, file: String::new(), line: 0 },
+            Symbol { kind: SymbolKind::Section, name: String::from(".data"), address: 0x102, size: 1, file: String::new(), line: 0 },
+        ];
+        let exe = vm64(&[0x00, 0x70, 0xff], &symbols, 0x100, None);
+
+        assert_eq!(&exe[..4], MAGIC);
+        assert_eq!(&exe[4..12], &[0, 1, 0, 2, 0, 0, 1, 0]);
+        assert_eq!(u32::from_be_bytes(exe[12..16].try_into().unwrap()), crc32(&exe[16..]));
+        assert_eq!(&exe[16..40], &[b'.', b't', b'e', b'x', b't', 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0x40, 0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&exe[64..], &[0x00, 0x70, 0xff]);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let exe = vm64(&[0x00, 0x70, 0xff], &symbols, 0x100, Some(b"VMRL"));
+        assert_eq!((&exe[6..8], &exe[64..88]), (&[0, 3][..], &[b'.', b'r', b'e', b'l', b'o', b'c', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x5B, 0, 0, 0, 4, 0, 0, 0, 4][..]));
+        assert_eq!(&exe[88..], b"\x00\x70\xffVMRL");
+    }
+}
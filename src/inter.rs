@@ -0,0 +1,176 @@
+use std::fs;
+
+use crate::{flow, map::{symbol_table, Symbol, SymbolKind}, Control, Line};
+
+/// Bytes of hex shown per listing row before wrapping.
+const ROW: usize = 8;
+
+/// Default number of `#image`/`#bytes` data bytes shown before the middle is elided.
+pub const DATA_LEN: usize = 10;
+
+/// Builds the `-inter` listing.
+///
+/// Every emitted byte of code is shown, wrapped over several rows if needed. Image and byte
+/// blobs show `data_len` bytes split between their start and end, or everything if `data_len`
+/// is 0. The listing ends with the symbol table and a cross reference.
+pub fn listing(instructions: &[Line], symbols: &[Symbol], align: usize, data_len: usize) -> String {
+    let source = |inst: &Line| {
+        let mut s = inst.2.clone();
+        for arg in inst.3.iter() {
+            s.push(' ');
+            s.push_str(arg);
+        }
+        s
+    };
+
+    let src_width = instructions.iter().map(|inst| source(inst).len()).max().unwrap_or(0);
+    let hex_width = ROW * 3 - 1;
+
+    let mut buf = String::new();
+
+    let mut index = align;
+    for inst in instructions.iter() {
+        let mut address = index;
+
+        // Directives take no space themselves, their data lands in the data section.
+        match &inst.0 {
+            Control::DataPointer(_) | Control::ImgDataPointer(_) => {
+                if let Some(s) = symbols.iter().find(|s| (s.kind == SymbolKind::Image || s.kind == SymbolKind::DataPointer) && s.name == inst.3[0]) {
+                    address = s.address;
+                }
+            }
+
+            _ => {}
+        }
+
+        let mut rows: Vec<(usize, String)> = Vec::new();
+
+        let hex = |address: usize, bytes: &[u8], rows: &mut Vec<(usize, String)>| {
+            for (i, chunk) in bytes.chunks(ROW).enumerate() {
+                rows.push((address + i * ROW, chunk.iter().map(|n| format!("{:02x}", n)).collect::<Vec<String>>().join(" ")));
+            }
+        };
+
+        if inst.0 == Control::Data && data_len != 0 && inst.1.len() > data_len {
+            let head = data_len.div_ceil(2);
+            let tail = data_len - head;
+
+            hex(index, &inst.1[..head], &mut rows);
+            rows.push((index + head, String::from(". . .")));
+            hex(index + inst.1.len() - tail, &inst.1[inst.1.len() - tail..], &mut rows);
+        }
+        else {
+            hex(index, &inst.1, &mut rows);
+        }
+
+        if rows.is_empty() { rows.push((address, String::new())) }
+
+        for (i, (address, hex)) in rows.into_iter().enumerate() {
+            if i == 0 {
+                buf.push_str(&format!("0x{:08x}  {:hex_width$}  {:src_width$}  {}:{}\n", address, hex, source(inst), inst.4, inst.5));
+            }
+            else {
+                buf.push_str(&format!("0x{:08x}  {}\n", address, hex));
+            }
+        }
+
+        index += inst.1.len();
+    }
+
+    buf.push('\n');
+    buf.push_str(&symbol_table(symbols));
+    buf.push('\n');
+    buf.push_str(&cross_reference(instructions, symbols));
+
+    buf
+}
+
+/// Lists where every label, image and data pointer is defined and referenced.
+fn cross_reference(instructions: &[Line], symbols: &[Symbol]) -> String {
+    let mut refs: Vec<(String, Vec<String>)> = Vec::new();
+
+    for s in symbols.iter().filter(|s| s.kind != SymbolKind::Section) {
+        if !refs.iter().any(|r| r.0 == s.name) { refs.push((s.name.clone(), Vec::new())) }
+    }
+
+    for inst in instructions.iter() {
+        let names = match inst.0 {
+            Control::ReqLabel =>        { inst.3.last().into_iter().collect() }
+            Control::ReqDataPointer =>  { inst.3.get(1).into_iter().collect() }
+            Control::ReqTable(_) =>     { inst.3.iter().collect() }
+            Control::ReqRelative(_) =>  { inst.3.get(1).into_iter().collect() }
+
+            _ => { Vec::new() }
+        };
+
+        for name in names.into_iter().filter(|n| !n.starts_with(flow::HIDDEN)) {
+            let location = format!("{}:{}", inst.4, inst.5);
+            match refs.iter_mut().find(|r| r.0 == *name) {
+                Some(r) => r.1.push(location),
+                None => refs.push((name.clone(), vec![location])),
+            }
+        }
+    }
+
+    let name_width = refs.iter().map(|r| r.0.len()).max().unwrap_or(0).max(4);
+
+    let mut buf = String::from("Cross reference\n");
+    buf.push_str(&format!("{:name_width$}  {:24}  {}\n", "name", "defined", "referenced"));
+    for (name, locations) in refs.iter() {
+        let defined = match symbols.iter().find(|s| s.kind != SymbolKind::Section && s.name == *name) {
+            Some(s) => format!("{}:{}", s.file, s.line),
+            None => String::from("undefined"),
+        };
+
+        buf.push_str(format!("{:name_width$}  {:24}  {}", name, defined, locations.join(" ")).trim_end());
+        buf.push('\n');
+    }
+
+    buf
+}
+
+pub fn write_listing(path: String, instructions: &[Line], symbols: &[Symbol], align: usize, data_len: usize) {
+    fs::write(path, listing(instructions, symbols, align, data_len)).unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::collect_symbols, tests::line};
+
+    #[test]
+    fn short_data_and_wrapping() {
+        let instructions = vec![
+            line(Control::Inst, vec![0xff, 0x01], "db", &["&ff", "&01"], 1),
+            line(Control::Inst, (0..10).collect(), "db", &["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"], 2),
+            line(Control::Data, vec![1, 2, 3], "#bytes", &["tiny", "tiny.bin"], 3),
+        ];
+        let symbols = collect_symbols(&instructions, 0);
+        let listing = listing(&instructions, &symbols, 0, DATA_LEN);
+        let lines: Vec<&str> = listing.lines().collect();
+
+        assert!(lines[0].starts_with("0x00000000  ff 01 "));
+        assert!(lines[1].starts_with("0x00000002  00 01 02 03 04 05 06 07  db 0"));
+        assert_eq!(lines[2], "0x0000000a  08 09");
+        assert!(lines[3].starts_with("0x0000000c  01 02 03 "));
+    }
+
+    #[test]
+    fn truncated_data_and_xref() {
+        let instructions = vec![
+            line(Control::Label, vec![], "loop:", &[], 1),
+            line(Control::ReqLabel, vec![0x50, 0, 0, 0, 0], "jmp", &["loop"], 2),
+            line(Control::ReqLabel, vec![0x50, 0, 0, 0, 0], "jmp", &["nowhere"], 3),
+            line(Control::Data, (0..20).collect(), "#bytes", &["blob", "blob.bin"], 4),
+        ];
+        let symbols = collect_symbols(&instructions, 0);
+        let listing = listing(&instructions, &symbols, 0, 4);
+
+        assert!(listing.contains("0x00000000                           loop:"));
+        assert!(listing.contains("0x0000000a  00 01                    #bytes blob blob.bin  main.asm:4\n"));
+        assert!(listing.contains("0x0000000c  . . .\n0x0000001c  12 13\n"));
+        assert!(listing.contains("loop     main.asm:1                main.asm:2\n"));
+        assert!(listing.contains("nowhere  undefined                 main.asm:3\n"));
+    }
+}
@@ -0,0 +1,122 @@
+use std::fs;
+
+use serde_json::{json, Value};
+
+use crate::{map::{Symbol, SymbolKind}, resolve_arg, Arg, Control, Line};
+
+fn control_name(control: &Control) -> &'static str {
+    match control {
+        Control::None =>                "none",
+        Control::Inst =>                "inst",
+        Control::Label =>               "label",
+        Control::ReqLabel =>            "req_label",
+        Control::Data =>                "data",
+        Control::DataPointer(_) =>      "data_pointer",
+        Control::ImgDataPointer(_) =>   "img_data_pointer",
+        Control::ReqDataPointer =>      "req_data_pointer",
+        Control::ReqOffset(_) =>        "req_offset",
+        Control::ReqTable(_) =>         "req_table",
+        Control::ReqRelative(_) =>      "req_relative",
+        Control::Test(_) =>             "test",
+        Control::EndTest =>             "end_test",
+        Control::Expect(_) =>           "expect",
+    }
+}
+
+fn operand(arg: &str) -> Value {
+    let (kind, value) = match resolve_arg(arg.to_owned()) {
+        Ok(Arg::Ureg(n)) =>     ("register", json!(n)),
+        Ok(Arg::Freg(n)) =>     ("float_register", json!(n)),
+        Ok(Arg::Liter(n)) =>    ("literal", json!(u64::from_be_bytes(n[..8].try_into().unwrap()))),
+        Ok(Arg::Label(_)) =>    ("symbol", json!(arg)),
+        Err(_) =>               ("invalid", Value::Null),
+    };
+
+    json!({"text": arg, "kind": kind, "value": value})
+}
+
+fn symbol(s: &Symbol) -> Value {
+    json!({"name": s.name, "kind": s.kind.name(), "address": s.address, "size": s.size, "file": s.file, "line": s.line})
+}
+
+/// Serialises every emitted item and the symbol table as JSON for editor plugins and scripts.
+pub fn program_json(instructions: &[Line], symbols: &[Symbol], align: usize) -> String {
+    let find = |name: &str| symbols.iter().find(|s| s.kind != SymbolKind::Section && s.name == name);
+
+    let mut items = Vec::new();
+
+    let mut index = align;
+    for inst in instructions.iter() {
+        let directive = inst.2.starts_with("#");
+
+        let operands = if directive || inst.0 == Control::Label {
+            inst.3.iter().map(|a| json!({"text": a, "kind": "text", "value": a})).collect::<Vec<Value>>()
+        }
+        else {
+            inst.3.iter().map(|a| operand(a)).collect::<Vec<Value>>()
+        };
+
+        let references = match inst.0 {
+            Control::ReqLabel =>        { inst.3.last().into_iter().collect() }
+            Control::ReqDataPointer =>  { inst.3.get(1).into_iter().collect() }
+            Control::DataPointer(_) | Control::ImgDataPointer(_) | Control::Data => { inst.3.first().into_iter().collect() }
+            Control::ReqTable(_) =>     { inst.3.iter().collect() }
+            Control::ReqRelative(_) =>  { inst.3.get(1).into_iter().collect() }
+
+            _ => { Vec::new() }
+        };
+        let references = references.iter().map(|name| json!({"name": name, "address": find(name).map(|s| s.address)})).collect::<Vec<Value>>();
+
+        items.push(json!({
+            "address":      index,
+            "size":         inst.1.len(),
+            "bytes":        inst.1.iter().map(|n| format!("{:02x}", n)).collect::<String>(),
+            "mnemonic":     inst.2,
+            "operands":     operands,
+            "control":      control_name(&inst.0),
+            "references":   references,
+            "file":         inst.4,
+            "line":         inst.5,
+        }));
+
+        index += inst.1.len();
+    }
+
+    let program = json!({
+        "version":  1,
+        "base":     align,
+        "items":    items,
+        "symbols":  symbols.iter().map(symbol).collect::<Vec<Value>>(),
+    });
+
+    format!("{}\n", serde_json::to_string_pretty(&program).unwrap())
+}
+
+pub fn write_json(path: String, instructions: &[Line], symbols: &[Symbol], align: usize) {
+    fs::write(path, program_json(instructions, symbols, align)).unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{map::collect_symbols, tests::line};
+
+    #[test]
+    fn items_and_symbols() {
+        let instructions = vec![
+            line(Control::Label, vec![], "loop:", &[], 1),
+            line(Control::ReqLabel, vec![0x50, 0x00, 0x00, 0x00, 0x10], "jmp", &["loop"], 2),
+            line(Control::Inst, vec![0x05, 0x01, 0, 0, 0, 0, 0, 0, 0, 0x2a], "mov", &["r1", "42"], 3),
+        ];
+        let json: Value = serde_json::from_str(&program_json(&instructions, &collect_symbols(&instructions, 16), 16)).unwrap();
+
+        assert_eq!(json["items"][1], json!({
+            "address": 16, "size": 5, "bytes": "5000000010", "mnemonic": "jmp",
+            "operands": [{"text": "loop", "kind": "symbol", "value": "loop"}],
+            "control": "req_label", "references": [{"name": "loop", "address": 16}], "file": "main.asm", "line": 2,
+        }));
+        assert_eq!(json["items"][2]["operands"], json!([{"text": "r1", "kind": "register", "value": 1}, {"text": "42", "kind": "literal", "value": 42}]));
+        assert_eq!(json["symbols"][1], json!({"name": "loop", "kind": "label", "address": 16, "size": 15, "file": "main.asm", "line": 1}));
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::{error, warning, Control, Line};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    Off,
+    Warn,
+    Error,
+}
+
+/// Every rule with what it flags. All rules warn unless configured otherwise.
+pub const RULES: &[(&str, &str)] = &[
+    ("unreachable",     "Code right after `jmp` or `hlt` without a label before it."),
+    ("unused-label",    "Labels nothing refers to, except labels before the first instruction."),
+    ("unused-data",     "`#image` and `#bytes` data nothing refers to."),
+    ("stack",           "`pop` without a `psh` on the path, or paths meeting with different stack depths."),
+    ("uninit",          "Registers that are read but never written."),
+    ("jump-to-data",    "Jumps to `#image`/`#bytes` names or to labels in front of `db`."),
+];
+
+pub type Config = HashMap<&'static str, Level>;
+
+/// A finding as (rule, line, message).
+pub type Finding = (&'static str, Line, String);
+
+/// Parses `<rule>=<off|warn|error>` pairs separated by commas, `all` sets every rule.
+pub fn parse_config(spec: &str) -> Result<Config, String> {
+    let mut config = Config::new();
+
+    for pair in spec.split(",").map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        let (rule, level) = pair.split_once("=").ok_or_else(|| format!("Expected <rule>=<off|warn|error>, got `{}`.", pair))?;
+        let level = match level {
+            "off" =>    Level::Off,
+            "warn" =>   Level::Warn,
+            "error" =>  Level::Error,
+
+            _ => return Err(format!("Unknown lint level `{}`, expected off, warn or error.", level)),
+        };
+
+        match rule {
+            "all" => RULES.iter().for_each(|(r, _)| { config.insert(r, level); }),
+            _ => {
+                let (rule, _) = RULES.iter().find(|(r, _)| *r == rule)
+                    .ok_or_else(|| format!("Unknown lint rule `{}`, expected one of {}.", rule, RULES.iter().map(|r| r.0).collect::<Vec<&str>>().join(", ")))?;
+                config.insert(rule, level);
+            }
+        }
+    }
+
+    Ok(config)
+}
+
+fn is_code(line: &Line) -> bool {
+    matches!(line.0, Control::Inst | Control::ReqLabel | Control::ReqDataPointer | Control::ReqOffset(_) | Control::ReqRelative(_)) && !line.1.is_empty()
+}
+
+fn is_db(line: &Line) -> bool {
+    line.2.eq_ignore_ascii_case("db")
+}
+
+/// A register as (float, index).
+pub type Reg = (bool, u8);
+
+/// Registers an encoded instruction reads and writes.
+pub fn registers(bytes: &[u8]) -> (Vec<Reg>, Vec<Reg>) {
+    let r = |i: usize| (false, bytes[i]);
+    let f = |i: usize| (true, bytes[i]);
+    let op = bytes[0];
+
+    match op {
+        0x01 => (vec![r(2)], vec![r(1)]),
+        0x02 => (vec![f(2)], vec![f(1)]),
+        0x03 => (vec![r(2)], vec![f(1)]),
+        0x04 => (vec![f(2)], vec![r(1)]),
+        0x05 => (vec![], vec![r(1)]),
+        0x06 => (vec![], vec![f(1)]),
+
+        0x07..=0x2A => {
+            let kind = match op {
+                0x07..=0x0E => op - 0x07,
+                0x0F..=0x16 => op - 0x0F,
+                0x17..=0x1A => op - 0x17 + 8,
+                0x1B..=0x1E => op - 0x1B + 8,
+                0x1F..=0x26 => op - 0x1F,
+
+                _ =>           op - 0x27 + 8,
+            };
+            let x = |i: usize| (kind % 2 == 1, bytes[i]);
+
+            match kind {
+                0 | 1 =>        (vec![], vec![x(1)]),
+                2 | 3 =>        (vec![x(6)], vec![]),
+                4 | 5 | 8 | 9 => (vec![r(1), x(3)], vec![]),
+
+                _ =>            (vec![r(3)], vec![x(1)]),
+            }
+        }
+
+        0x30..=0x39 => {
+            let x = |i: usize| (op % 2 == 1, bytes[i]);
+            (vec![x(2), x(3)], vec![x(1)])
+        }
+        0x3A..=0x3E => (vec![r(2), r(3)], vec![r(1)]),
+        0x3F => (vec![r(2)], vec![r(1)]),
+        0x40 | 0x41 | 0x46 | 0x47 => (vec![r(1)], vec![r(1)]),
+        0x42 => (vec![r(1)], vec![]),
+        0x43 => (vec![f(1)], vec![]),
+        0x44 => (vec![], vec![r(1)]),
+        0x45 => (vec![], vec![f(1)]),
+
+        0x51 | 0x5F | 0x61 | 0x72 => (vec![r(1)], vec![]),
+        0x52..=0x5D => {
+            let x = |i: usize| ((op - 0x52) % 4 >= 2, bytes[i]);
+            let mut reads = vec![x(1), x(2)];
+            if op % 2 == 1 { reads.push(r(3)) }
+            (reads, vec![])
+        }
+        0x73 | 0x74 => (vec![], vec![r(1)]),
+
+        0x83 => (vec![r(1), r(2)], vec![]),
+        0x84 => (vec![r(1), r(2), r(3)], vec![]),
+        0x90 => (vec![r(1)], vec![]),
+        0x91 => (vec![r(1), r(2)], vec![]),
+        0x92 => (vec![], vec![r(1)]),
+        0x93 => (vec![r(2)], vec![r(1)]),
+        0xA0 => (vec![r(5)], vec![]),
+        0xA1 => ((1..7).map(r).collect(), vec![]),
+
+        _ => (vec![], vec![]),
+    }
+}
+
+/// Where execution can continue after line `i`, jumps only when their target is a label.
+fn successors(instructions: &[Line], labels: &HashMap<&str, usize>, i: usize) -> Vec<usize> {
+    let line = &instructions[i];
+    let target = match line.0 {
+        Control::ReqLabel => labels.get(line.3.last().unwrap().as_str()).copied(),
+
+        _ => None,
+    };
+
+    let next = match (is_code(line) && !is_db(line)).then(|| line.1[0]) {
+        Some(0x70 | 0x51) =>    vec![],
+        Some(0x50) =>           target.into_iter().collect(),
+        Some(0x52..=0x5E | 0x60) => std::iter::once(i + 1).chain(target).collect(),
+
+        _ => vec![i + 1],
+    };
+
+    next.into_iter().filter(|j| *j < instructions.len() && instructions[*j].0 != Control::Data).collect()
+}
+
+/// Runs every rule over linked instructions.
+pub fn findings(instructions: &[Line]) -> Vec<Finding> {
+    let mut findings: Vec<Finding> = Vec::new();
+
+    let labels: HashMap<&str, usize> = instructions.iter().enumerate()
+        .filter(|(_, l)| l.0 == Control::Label)
+        .map(|(i, l)| (l.2.strip_suffix(":").unwrap(), i))
+        .collect();
+    let data: HashSet<&str> = instructions.iter()
+        .filter(|l| matches!(l.0, Control::ImgDataPointer(_) | Control::DataPointer(_)))
+        .filter_map(|l| l.3.first().map(|n| n.as_str()))
+        .collect();
+    let referenced: HashSet<&str> = instructions.iter()
+        .filter(|l| !matches!(l.0, Control::ImgDataPointer(_) | Control::DataPointer(_) | Control::Data))
+        .flat_map(|l| l.3.iter().map(|a| a.as_str()))
+        .collect();
+    let first_code = instructions.iter().position(is_code).unwrap_or(instructions.len());
+
+    for (i, line) in instructions.iter().enumerate() {
+        match &line.0 {
+            Control::Label => {
+                let name = line.2.strip_suffix(":").unwrap();
+                if i > first_code && !referenced.contains(name) {
+                    findings.push(("unused-label", line.clone(), format!("Label `{}` is never used.", name)));
+                }
+            }
+            Control::ImgDataPointer(_) | Control::DataPointer(_) => {
+                if let Some(name) = line.3.first().filter(|n| !referenced.contains(n.as_str())) {
+                    findings.push(("unused-data", line.clone(), format!("`{}` is never used.", name)));
+                }
+            }
+            Control::ReqLabel if is_code(line) && (0x50..=0x60).contains(&line.1[0]) => {
+                let target = line.3.last().unwrap();
+                let into_db = labels.get(target.as_str())
+                    .and_then(|l| instructions[*l..].iter().find(|n| is_code(n) || matches!(n.0, Control::ReqTable(_))))
+                    .is_some_and(|n| is_db(n) || matches!(n.0, Control::ReqTable(_)));
+
+                if data.contains(target.as_str()) || into_db {
+                    findings.push(("jump-to-data", line.clone(), format!("Jump to data `{}`.", target)));
+                }
+            }
+
+            _ => {}
+        }
+
+        // The return point of a `call` follows its `jmp`.
+        if i > 0 && is_code(line) && !is_db(line) && !(i >= 3 && matches!(instructions[i - 3].0, Control::ReqOffset(_))) {
+            let previous = &instructions[i - 1];
+            if is_code(previous) && !is_db(previous) && matches!(previous.1[0], 0x50 | 0x51 | 0x70) {
+                findings.push(("unreachable", line.clone(), format!("Unreachable, nothing jumps here after `{}`.", previous.2)));
+            }
+        }
+    }
+
+    // Registers read somewhere but written nowhere.
+    let code = instructions.iter().filter(|l| is_code(l) && !is_db(l));
+    let written: HashSet<Reg> = code.clone().flat_map(|l| registers(&l.1).1).collect();
+    let mut reported = HashSet::new();
+    for line in code {
+        for reg in registers(&line.1).0.into_iter().filter(|r| !written.contains(r)) {
+            if reported.insert(reg) {
+                findings.push(("uninit", line.clone(), format!("`{}{:x}` is read but never written.", if reg.0 { "f" } else { "r" }, reg.1)));
+            }
+        }
+    }
+
+    // Stack depth along every path from the first line.
+    let mut depth: Vec<Option<usize>> = vec![None; instructions.len()];
+    let mut conflicts = HashSet::new();
+    let mut work = if instructions.is_empty() { vec![] } else { vec![(0, 0)] };
+    while let Some((i, d)) = work.pop() {
+        match depth[i] {
+            Some(e) if e == d => continue,
+            Some(e) => {
+                if conflicts.insert(i) {
+                    findings.push(("stack", instructions[i].clone(), format!("Paths meet here with {} and {} values on the stack.", e.min(d), e.max(d))));
+                }
+                continue
+            }
+            None => depth[i] = Some(d),
+        }
+
+        let line = &instructions[i];
+        let d = match (is_code(line) && !is_db(line)).then(|| line.1[0]) {
+            Some(0x42 | 0x43) => d + 1,
+            Some(0x44 | 0x45) if d == 0 => {
+                findings.push(("stack", line.clone(), String::from("`pop` without a value pushed on this path.")));
+                0
+            }
+            Some(0x44 | 0x45) => d - 1,
+
+            _ => d,
+        };
+
+        // A `call` returns with the stack as it was, the callee starts with the return address.
+        if let Control::ReqOffset(_) = line.0 {
+            work.extend(successors(instructions, &labels, i + 2).into_iter().map(|j| (j, 1)));
+            if i + 3 < instructions.len() && instructions[i + 3].0 != Control::Data { work.push((i + 3, d)) }
+            continue
+        }
+        work.extend(successors(instructions, &labels, i).into_iter().map(|j| (j, d)));
+    }
+
+    findings
+}
+
+/// Reports the findings through `error()` and `warning()` at their configured level,
+/// returns how many were reported.
+pub fn lint(instructions: &[Line], config: &Config) -> usize {
+    let mut count = 0;
+
+    for (rule, line, message) in findings(instructions) {
+        match config.get(rule).copied().unwrap_or(Level::Warn) {
+            Level::Off => continue,
+            Level::Warn => warning(line, &format!("{} [{}]", message, rule)),
+            Level::Error => error(line, &format!("{} [{}]", message, rule)),
+        }
+        count += 1;
+    }
+
+    count
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex_source, link};
+
+    #[test]
+    fn rules() {
+        let source = indoc::indoc! {"
+            start:
+            psh r1
+            jlg r0 r2 skip
+            pop r3
+            skip:
+            pop r4
+            jmp table
+            hlt
+            unused:
+            hlt
+            table:
+            db 1 2
+        "};
+        let (instructions, _) = link(lex_source(source, "test.asm"), ".", 0);
+
+        let found = findings(&instructions).into_iter().map(|f| (f.0, f.1.5)).collect::<Vec<(&str, usize)>>();
+        assert_eq!(found.iter().filter(|f| f.0 == "unused-label").collect::<Vec<_>>(), vec![&("unused-label", 9)]);
+        assert!(found.contains(&("jump-to-data", 7)));
+        assert!(found.contains(&("unreachable", 8)));
+        assert!(found.contains(&("uninit", 2)));
+        assert!(found.contains(&("uninit", 3)));
+        assert!(found.contains(&("stack", 5)));
+        assert!(!found.contains(&("uninit", 4)));
+    }
+
+    #[test]
+    fn config() {
+        let config = parse_config("all=off, stack=error").unwrap();
+        assert_eq!((config["unreachable"], config["stack"]), (Level::Off, Level::Error));
+        assert!(parse_config("stak=off").unwrap_err().contains("Unknown lint rule"));
+        assert!(parse_config("stack=loud").is_err());
+    }
+}
@@ -0,0 +1,423 @@
+use serde_json::{json, Value};
+use std::{collections::{HashMap, HashSet}, fs, io::{self, BufRead, Write}, path::Path};
+
+use crate::{collect_errors, devices, get_all_files, lex_source, link, lint, preprocess, testing, Control, Diagnostic, Line};
+
+/// Every mnemonic with its operand forms, shown by completion.
+pub const MNEMONICS: &[(&str, &str)] = &[
+    ("nop",     ""),
+    ("mov",     "r r | f f | f r | r f | r imm | f imm | r/f len addr | addr r/f len | r r/f len | r/f len r | off r r/f len | r/f len off r"),
+    ("mva",     "r/f len addr | addr r/f len | r r/f len | r/f len r | off r r/f len | r/f len off r"),
+    ("mvd",     "r/f len addr | addr r/f len | r r/f len | r/f len r | off r r/f len | r/f len off r"),
+    ("add",     "r r r | f f f"),
+    ("sub",     "r r r | f f f"),
+    ("mul",     "r r r | f f f"),
+    ("div",     "r r r | f f f"),
+    ("mod",     "r r r | f f f"),
+    ("shl",     "r r r"),
+    ("shr",     "r r r"),
+    ("and",     "r r r"),
+    ("or",      "r r r"),
+    ("xor",     "r r r"),
+    ("not",     "r r"),
+    ("inc",     "r"),
+    ("dec",     "r"),
+    ("psh",     "r | f"),
+    ("pop",     "r | f"),
+    ("adc",     "r"),
+    ("sbc",     "r"),
+    ("scf",     ""),
+    ("ccf",     ""),
+    ("jmp",     "label | addr | r"),
+    ("jlg",     "r r label | r r r | f f label | f f r"),
+    ("jpe",     "r r label | r r r | f f label | f f r"),
+    ("jne",     "r r label | r r r | f f label | f f r"),
+    ("jpc",     "label | addr | r"),
+    ("jnc",     "label | addr | r"),
+    ("hlt",     ""),
+    ("wit",     "imm | r"),
+    ("gst",     "r"),
+    ("gpc",     "r"),
+    ("syscall", "(none) | imm | r"),
+    ("sysret",  ""),
+    ("memcpy",  "addr addr len | r r len | r r r"),
+    ("out",     "r port | r r"),
+    ("in",      "r port | r r"),
+    ("grapcpy", "r image x y w h | r addr x y w h | r r r r r r"),
+    ("db",      "imm ..."),
+    ("call",    "label | addr | r"),
+    ("ret",     ""),
+    ("la",      "r label"),
+    ("break",   ""),
+    ("continue", ""),
+];
+
+pub const DIRECTIVES: &[(&str, &str)] = &[
+    ("#image",   "name path"),
+    ("#bytes",   "name path"),
+    ("#test",    "name"),
+    ("#endtest", ""),
+    ("#expect",  "target ==|!= value"),
+    ("#if",      "r ==|!=|<|>|<=|>= r | expr"),
+    ("#ifdef",   "name"),
+    ("#ifndef",  "name"),
+    ("#elif",    "expr"),
+    ("#else",    ""),
+    ("#endif",   ""),
+    ("#while",   "r ==|!=|<|>|<=|>= r"),
+    ("#endwhile", ""),
+    ("#loop",    "r imm | r r"),
+    ("#endloop", ""),
+    ("#rep",     "count"),
+    ("#endrep",  ""),
+    ("#for",     "name in start..end"),
+    ("#endfor",  ""),
+    ("#struct",  "Name { field: size, ... }"),
+    ("#instance", "Name label { field: value, ... }"),
+    ("#import",  "vm64 [version]"),
+    ("#table",   "name [2|4|8] label ..."),
+];
+
+pub fn uri_to_path(uri: &str) -> String {
+    let path = uri.strip_prefix("file://").unwrap_or(uri);
+
+    let mut bytes = Vec::new();
+    let mut i = 0;
+    while i < path.len() {
+        match u8::from_str_radix(path.get(i + 1..i + 3).filter(|_| path.as_bytes()[i] == b'%').unwrap_or("-"), 16) {
+            Ok(n) => { bytes.push(n); i += 3 }
+            Err(_) => { bytes.push(path.as_bytes()[i]); i += 1 }
+        }
+    }
+
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Paths of documents that weren't files, left as their URI by `uri_to_path`, stay as they are.
+pub fn path_to_uri(path: &str) -> String {
+    if !path.starts_with("/") && path.contains(":") { return path.to_owned() }
+    format!("file://{}", path.replace("%", "%25").replace(" ", "%20"))
+}
+
+/// Byte offset of `character`, counted in UTF-16 code units like LSP positions, clamped to the
+/// end of `line`.
+fn byte_offset(line: &str, character: usize) -> usize {
+    let mut units = 0;
+    for (i, c) in line.char_indices() {
+        if units >= character { return i }
+        units += c.len_utf16();
+    }
+    line.len()
+}
+
+/// The operand or mnemonic under `character`, separated like the lexer separates them. A label
+/// definition gives its name without the `:`.
+fn word_at(text: &str, line: usize, character: usize) -> Option<String> {
+    let line = text.lines().nth(line)?;
+    let code = line.split(";").next().unwrap();
+    let split = |c: char| c.is_whitespace() || c == ',';
+
+    let start = code[..byte_offset(line, character).min(code.len())].rfind(split).map(|i| i + 1).unwrap_or(0);
+    let end = code[start..].find(split).map(|i| start + i).unwrap_or(code.len());
+
+    if start >= end { None } else { Some(code[start..end].trim_end_matches(":").to_owned()) }
+}
+
+fn location(line: &Line) -> Value {
+    json!({ "uri": path_to_uri(&line.4), "range": { "start": { "line": line.5 - 1, "character": 0 }, "end": { "line": line.5 - 1, "character": 0 } } })
+}
+
+fn name(line: &Line) -> Option<&str> {
+    match &line.0 {
+        Control::Label => line.2.strip_suffix(":"),
+        Control::ImgDataPointer(_) | Control::DataPointer(_) => line.3.first().map(|n| n.as_str()),
+
+        _ => None,
+    }
+}
+
+/// A linked folder, every instruction with its address.
+struct Project {
+    instructions: Vec<(usize, Line)>,
+    errors: Vec<Diagnostic>,
+    files: Vec<String>,
+}
+
+pub struct Server {
+    documents: HashMap<String, String>,
+    published: HashSet<String>,
+    align: usize,
+}
+
+impl Server {
+    pub fn new(align: usize) -> Server {
+        Server { documents: HashMap::new(), published: HashSet::new(), align }
+    }
+
+    /// Assembles the folder of `path`, open documents are used instead of their files.
+    fn project(&self, path: &str) -> Project {
+        let folder = Path::new(path).parent().map(|p| p.display().to_string()).unwrap_or_default();
+
+        // Documents that aren't files, like `untitled:Untitled-1`, are assembled on their own.
+        let mut files = get_all_files(folder.clone()).unwrap_or_default().iter()
+            .filter(|p| p.extension().is_some_and(|e| e == "asm"))
+            .map(|p| p.display().to_string())
+            .collect::<Vec<String>>();
+        if !files.contains(&path.to_owned()) { files.push(path.to_owned()) }
+
+        let (instructions, errors) = collect_errors(|| {
+            let sources = files.iter().map(|file| {
+                (file.clone(), self.documents.get(file).cloned().or_else(|| fs::read_to_string(file).ok()).unwrap_or_default())
+            }).collect();
+
+            let mut instructions = Vec::new();
+            for (file, text) in preprocess(sources, &HashMap::new()) {
+                instructions.append(&mut lex_source(&text, &file));
+            }
+            let instructions = link(testing::strip_tests(instructions), &folder, self.align).0;
+            lint::lint(&instructions, &lint::Config::new());
+            instructions
+        });
+
+        let mut index = self.align;
+        let instructions = instructions.into_iter().map(|inst| {
+            let address = index;
+            index += inst.1.len();
+            (address, inst)
+        }).collect();
+
+        Project { instructions, errors, files }
+    }
+
+    fn diagnostics(&mut self, path: &str) -> Vec<Value> {
+        let project = self.project(path);
+
+        let mut by_file: HashMap<String, Vec<Value>> = project.files.iter().map(|f| (f.clone(), Vec::new())).collect();
+        for (line, message, error) in project.errors.iter() {
+            let row = line.5.saturating_sub(1);
+            by_file.entry(line.4.clone()).or_default().push(json!({
+                "range": { "start": { "line": row, "character": 0 }, "end": { "line": row, "character": 1000 } },
+                "severity": if *error { 1 } else { 2 },
+                "source": "asm",
+                "message": message,
+            }));
+        }
+        for file in self.published.drain() {
+            by_file.entry(file).or_default();
+        }
+
+        by_file.into_iter().map(|(file, diagnostics)| {
+            if !diagnostics.is_empty() { self.published.insert(file.clone()); }
+            json!({ "jsonrpc": "2.0", "method": "textDocument/publishDiagnostics", "params": { "uri": path_to_uri(&file), "diagnostics": diagnostics } })
+        }).collect()
+    }
+
+    fn definition(&self, path: &str, word: &str) -> Value {
+        let project = self.project(path);
+        project.instructions.iter().find(|(_, inst)| name(inst) == Some(word)).map(|(_, inst)| location(inst)).unwrap_or(Value::Null)
+    }
+
+    fn references(&self, path: &str, word: &str, declaration: bool) -> Value {
+        let project = self.project(path);
+        let mut seen = HashSet::new();
+
+        Value::Array(project.instructions.iter()
+            .filter(|(_, inst)| inst.0 != Control::Data)
+            .filter(|(_, inst)| if name(inst) == Some(word) { declaration } else { inst.3.iter().any(|a| a == word) })
+            .filter(|(_, inst)| seen.insert((inst.4.clone(), inst.5)))
+            .map(|(_, inst)| location(inst))
+            .collect())
+    }
+
+    fn hover(&self, path: &str, line: usize, word: Option<&str>) -> Value {
+        let project = self.project(path);
+        let bytes = |b: &[u8]| b.iter().take(32).map(|n| format!("{:02x}", n)).collect::<Vec<String>>().join(" ") + if b.len() > 32 { " ..." } else { "" };
+
+        // A symbol under the cursor, else the instruction on the line.
+        let symbol = word.and_then(|w| project.instructions.iter().find(|(_, inst)| name(inst) == Some(w) && inst.0 == Control::Label)
+            .map(|(address, _)| format!("label `{}` at `0x{:08x}`", w, address))
+            .or_else(|| project.instructions.iter().find(|(_, inst)| inst.0 == Control::Data && inst.3.first().map(|n| n.as_str()) == Some(w))
+                .map(|(address, inst)| format!("{} `{}` at `0x{:08x}`, {} bytes", if inst.2 == "#image" { "image" } else { "bytes" }, w, address, inst.1.len())))
+            .or_else(|| devices::lookup(w).map(|(version, name, value, about)| format!("`{}` = `0x{:x}`, {} since {}\n\n{}", name, value, devices::MODULE, version, about))));
+
+        let text = symbol.or_else(|| project.instructions.iter().find(|(_, inst)| inst.4 == path && inst.5 == line + 1 && !inst.1.is_empty() && inst.0 != Control::Data)
+            .map(|(address, inst)| format!("`{} {}`\n\naddress `0x{:08x}`, {} bytes\n\n`{}`", inst.2, inst.3.join(" "), address, inst.1.len(), bytes(&inst.1))));
+
+        match text {
+            Some(text) => json!({ "contents": { "kind": "markdown", "value": text } }),
+            None => Value::Null,
+        }
+    }
+
+    fn completion(&self, path: &str, line: usize, character: usize) -> Value {
+        let text = self.documents.get(path).cloned().unwrap_or_default();
+        let before = text.lines().nth(line).map(|l| &l[..byte_offset(l, character)]).unwrap_or("");
+
+        // The first word completes mnemonics, the operands complete symbols.
+        if before.trim_start().contains(char::is_whitespace) {
+            let project = self.project(path);
+            let mut names = project.instructions.iter().filter_map(|(_, inst)| name(inst)).collect::<Vec<&str>>();
+            names.dedup();
+
+            return Value::Array(names.iter().map(|n| json!({ "label": n, "kind": 18 })).collect());
+        }
+
+        Value::Array(MNEMONICS.iter().map(|(n, forms)| json!({ "label": n, "kind": 14, "detail": forms }))
+            .chain(DIRECTIVES.iter().map(|(n, forms)| json!({ "label": n, "kind": 14, "detail": forms })))
+            .collect())
+    }
+
+    /// Handles one message, returns the responses and notifications to send.
+    pub fn handle(&mut self, message: &Value) -> Vec<Value> {
+        let method = message["method"].as_str().unwrap_or("");
+        let params = &message["params"];
+        let path = uri_to_path(params["textDocument"]["uri"].as_str().unwrap_or(""));
+        let (line, character) = (params["position"]["line"].as_u64().unwrap_or(0) as usize, params["position"]["character"].as_u64().unwrap_or(0) as usize);
+        let word = self.documents.get(&path).and_then(|text| word_at(text, line, character));
+
+        let result = match method {
+            "initialize" => json!({
+                "capabilities": {
+                    "textDocumentSync": 1,
+                    "definitionProvider": true,
+                    "referencesProvider": true,
+                    "hoverProvider": true,
+                    "completionProvider": { "triggerCharacters": [" "] },
+                },
+                "serverInfo": { "name": "asm" },
+            }),
+            "shutdown" => Value::Null,
+
+            "textDocument/didOpen" => {
+                self.documents.insert(path.clone(), params["textDocument"]["text"].as_str().unwrap_or("").to_owned());
+                return self.diagnostics(&path);
+            }
+            "textDocument/didChange" => {
+                if let Some(text) = params["contentChanges"].as_array().and_then(|c| c.last()).and_then(|c| c["text"].as_str()) {
+                    self.documents.insert(path.clone(), text.to_owned());
+                }
+                return self.diagnostics(&path);
+            }
+            "textDocument/didSave" => return self.diagnostics(&path),
+            "textDocument/didClose" => { self.documents.remove(&path); return Vec::new() }
+
+            "textDocument/definition" => word.map(|w| self.definition(&path, &w)).unwrap_or(Value::Null),
+            "textDocument/references" => word.map(|w| self.references(&path, &w, params["context"]["includeDeclaration"].as_bool().unwrap_or(true))).unwrap_or(Value::Null),
+            "textDocument/hover" => self.hover(&path, line, word.as_deref()),
+            "textDocument/completion" => self.completion(&path, line, character),
+
+            _ if message.get("id").is_none() => return Vec::new(),
+            _ => return vec![json!({ "jsonrpc": "2.0", "id": message["id"], "error": { "code": -32601, "message": format!("Unknown method `{}`.", method) } })],
+        };
+
+        match message.get("id") {
+            Some(id) => vec![json!({ "jsonrpc": "2.0", "id": id, "result": result })],
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Reads one `Content-Length` framed message.
+fn read_message(input: &mut impl BufRead) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 { return None }
+
+        let header = header.trim();
+        if header.is_empty() { break }
+        if let Some(n) = header.strip_prefix("Content-Length:") { length = n.trim().parse::<usize>().ok() }
+    }
+
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+/// Serves the language server protocol over stdin and stdout until `exit`.
+pub fn serve(align: usize) {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut server = Server::new(align);
+
+    while let Some(message) = read_message(&mut input) {
+        if message["method"] == "exit" { break }
+
+        for reply in server.handle(&message) {
+            let body = reply.to_string();
+            write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        output.flush().unwrap();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_and_uris() {
+        assert_eq!(word_at("  jmp loop ; again", 0, 8).as_deref(), Some("loop"));
+        assert_eq!(word_at("jlg r0,r1 done", 0, 7).as_deref(), Some("r1"));
+        assert_eq!(word_at("loop:", 0, 2).as_deref(), Some("loop"));
+        assert_eq!(word_at("hlt ; loop", 0, 7), None);
+        assert_eq!(word_at("hlt ; é", 0, 7), None);
+        assert_eq!(word_at("jmp ünder ; 😀", 0, 6).as_deref(), Some("ünder"));
+        assert_eq!((byte_offset("a😀b", 1), byte_offset("a😀b", 2), byte_offset("a😀b", 3), byte_offset("a😀b", 9)), (1, 5, 5, 6));
+        assert_eq!(uri_to_path("file:///home/a%20b/main.asm"), "/home/a b/main.asm");
+        assert_eq!(path_to_uri("/home/a b/main.asm"), "file:///home/a%20b/main.asm");
+    }
+
+    #[test]
+    fn diagnostics_and_navigation() {
+        let dir = std::env::temp_dir().join(format!("vm64-lsp-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("main.asm").display().to_string();
+        let uri = path_to_uri(&path);
+
+        let mut server = Server::new(0x1000);
+        let open = server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": uri, "text": "loop:\njmp loop\njmp nowhere\n" } } }));
+        let position = |line: usize, character: usize| json!({ "textDocument": { "uri": uri }, "position": { "line": line, "character": character }, "context": { "includeDeclaration": true } });
+        let definition = server.handle(&json!({ "jsonrpc": "2.0", "id": 1, "method": "textDocument/definition", "params": position(1, 5) }));
+        let references = server.handle(&json!({ "jsonrpc": "2.0", "id": 2, "method": "textDocument/references", "params": position(0, 1) }));
+        let hover = server.handle(&json!({ "jsonrpc": "2.0", "id": 3, "method": "textDocument/hover", "params": position(2, 1) }));
+        server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didChange", "params": { "textDocument": { "uri": uri }, "contentChanges": [{ "text": "hlt ; é\n" }] } }));
+        let completion = server.handle(&json!({ "jsonrpc": "2.0", "id": 4, "method": "textDocument/completion", "params": position(0, 7) }));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let diagnostics = &open[0]["params"]["diagnostics"];
+        assert_eq!((diagnostics[0]["message"].as_str(), diagnostics[0]["range"]["start"]["line"].as_u64()), (Some("Undefined label."), Some(2)));
+        assert_eq!(definition[0]["result"]["range"]["start"]["line"], 0);
+        assert_eq!(references[0]["result"].as_array().unwrap().len(), 2);
+        assert!(hover[0]["result"]["contents"]["value"].as_str().unwrap().contains("address `0x00001005`, 5 bytes"));
+        assert!(completion[0]["result"].as_array().is_some());
+    }
+
+    #[test]
+    fn half_typed_directives() {
+        let dir = std::env::temp_dir().join(format!("vm64-lsp-directives-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let uri = path_to_uri(&dir.join("main.asm").display().to_string());
+
+        let mut server = Server::new(0x1000);
+        let open = server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": uri, "text": "#image\n#bytes data\nhlt\n" } } }));
+        fs::remove_dir_all(&dir).unwrap();
+
+        let diagnostics = open[0]["params"]["diagnostics"].as_array().unwrap();
+        assert_eq!(diagnostics.iter().map(|d| (d["range"]["start"]["line"].as_u64().unwrap(), d["message"].as_str().unwrap())).collect::<Vec<_>>(), vec![
+            (0, "Invalid number of arguments."),
+            (1, "Invalid number of arguments."),
+        ]);
+    }
+
+    #[test]
+    fn untitled_documents() {
+        let mut server = Server::new(0x1000);
+        let open = server.handle(&json!({ "jsonrpc": "2.0", "method": "textDocument/didOpen", "params": { "textDocument": { "uri": "untitled:Untitled-1", "text": "jmp nowhere\n" } } }));
+
+        assert_eq!(open[0]["params"]["uri"], "untitled:Untitled-1");
+        assert_eq!(open[0]["params"]["diagnostics"][0]["message"], "Undefined label.");
+    }
+}
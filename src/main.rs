@@ -1,131 +1,314 @@
 use colored::Colorize;
 use indoc::indoc;
-use std::{collections::HashMap, fs, path::PathBuf};
+use std::{cell::RefCell, collections::HashMap, fs, path::{Path, PathBuf}, sync::atomic::{AtomicUsize, Ordering}};
 use image::ImageReader;
 
+mod cli;
+mod conditional;
+mod debug;
+mod devices;
+mod disasm;
+mod drive;
+mod expr;
+mod fb;
+mod flow;
+mod fmt;
+mod format;
+mod inter;
+mod json;
+mod lint;
+mod lsp;
+mod manifest;
+mod map;
+mod peephole;
+mod pic;
+mod reloc;
+mod repeat;
+mod sim;
+mod structs;
+mod testing;
+mod watch;
+
 fn print_usage() {
     let uasge = indoc! {"
-        Usage:
-            -i      <input_folder>
-            -o      <output_file>
-
-            -cfg    <file_path>             File for more verbose build arguments.
-            -inter  <output_file>           Generates intermediate represantation.
-            -align  <alignment in hex>      Used for aligning labels in absolute mode.
+        Usage: asm <command> [flags]
+
+            build   Assembles the input folder, the default when no command is given.
+            run     Assembles and runs the program in the headless simulator.
+            test    Runs the #test blocks in the simulator.
+            check   Reports errors and lint warnings without writing anything.
+            fmt     Formats the sources in place, --check only reports.
+            disasm  Disassembles a flat binary or vm64 executable.
+            lsp     Language server over stdio for editors.
+
+        Run `asm <command> --help` for the flags of a command. Without -i the target from
+        vm64.toml is used. Flags from a -cfg file override the target and flags given on
+        the command line override both.
     "};
 
     println!("{}", uasge);
 }
 
-fn resolve_args(args: Vec<String>) -> Vec<Option<String>> {
-    let mut sorted_args = Vec::new();
-
-    let mut input_path = None;
-    let mut output_path = None;
-    let mut cfg_path = None;
-    let mut inter_path = None;
-    let mut alignment = None;
-
-    let mut i = 0;
-    while i < args.len() {
-        match args[i].as_ref() {
-            "-i" => {
-                input_path = Some(args[i + 1].clone());
-                i += 1;
-            }
-            "-o" => {
-                output_path = Some(args[i + 1].clone());
-                i += 1;
-            }
-            "-cfg" => {
-                cfg_path = Some(args[i + 1].clone());
-                i += 1;
-            }
-            "-inter" => {
-                inter_path = Some(args[i + 1].clone());
-                i += 1;
-            }
-            "-align" => {
-                alignment = Some(args[i + 1].clone());
-                i += 1;
-            }
-
-            _ => {}
-        }
-        i += 1;
-    }
-
-    sorted_args.push(input_path);
-    sorted_args.push(output_path);
-    sorted_args.push(cfg_path);
-    sorted_args.push(inter_path);
-    sorted_args.push(alignment);
-
-    return sorted_args;
-}
-
-fn get_all_files(path: String) -> Vec<PathBuf> {
+fn get_all_files(path: String) -> std::io::Result<Vec<PathBuf>> {
     let mut file_paths = Vec::new();
 
-    for entry in fs::read_dir(path).unwrap() {
-        let entry = entry.unwrap();
+    for entry in fs::read_dir(path)? {
+        let entry = entry?;
         let path = entry.path();
         if path.is_file() {
             file_paths.push(path);
         }
         else {
-            file_paths.append(&mut get_all_files(path.to_str().unwrap().to_string()));
+            file_paths.append(&mut get_all_files(path.to_str().unwrap().to_string())?);
         }
     }
 
-    return file_paths;
+    Ok(file_paths)
+}
+
+/// Source files of the input, a folder or a single file, followed by the include folders.
+fn source_files(input_path: &str, include: &[String]) -> Result<Vec<PathBuf>, String> {
+    let folder = |path: &str| get_all_files(path.to_owned()).map_err(|_| format!("Unable to read folder `{}`.", path));
+    let mut paths = if Path::new(input_path).is_file() { vec![PathBuf::from(input_path)] } else { folder(input_path)? };
+
+    for path in include.iter() {
+        paths.append(&mut folder(path)?);
+    }
+
+    Ok(paths)
+}
+
+/// Folder `#image` and `#bytes` paths are relative to.
+fn asset_dir(input_path: &str) -> String {
+    match Path::new(input_path).is_file() {
+        true => Path::new(input_path).parent().unwrap().display().to_string(),
+        false => input_path.to_owned(),
+    }
 }
 
 type Line = (Control, Vec<u8>, String, Vec<String>, String, usize);
 
 fn main() {
-    let args: Vec<String> = std::env::args().collect();
-    if args.len() < 2 || args[1] == "help" || args[1] == "-h" {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.is_empty() || args[0] == "help" || args[0] == "-h" || args[0] == "--help" {
         print_usage();
         return;
     }
 
-    let mut args = resolve_args(args);
-    let mut input_path = args[0].clone();
-    let mut output_path = args[1].clone();
-    let     cfg_path = args[2].clone();
-    let mut inter_path = args[3].clone();
-    let mut alignment = args[4].clone();
-    let mut align = 0;
+    let (command, options, target) = cli::parse(&args).unwrap_or_else(|e| {
+        let command = cli::COMMANDS.iter().find(|c| c.name == args[0]).or(if args[0].starts_with("-") { cli::COMMANDS.first() } else { None });
+        cli::fail(command, &e)
+    });
+    if options.contains_key("--help") {
+        print!("{}", cli::usage(command));
+        return;
+    }
+
+    let align = cli::or_fail(command, cli::hex(&options, "-align")).unwrap_or(0);
+    let limit = cli::or_fail(command, cli::number(&options, "-limit")).unwrap_or(1_000_000);
+    let pic = cli::switch(&options, "--pic");
 
-    if let Some(cfg_path) = cfg_path {
-        let cfg = fs::read_to_string(cfg_path).expect("Unable to read config file.")
-            .replace("\n", " ")
-            .replace("\r", "")
-            .replace("  ", " ")
-            .replace(", ", " ")
-            .replace(",", " ");
-        args = resolve_args(cfg.split(" ").map(|s| s.trim().to_owned()).collect());
+    match command.name {
+        "test" => {
+            std::process::exit(if testing::run_tests(&options["-i"], &target.include, &target.defines, align, limit) { 0 } else { 1 });
+        }
+        "check" => {
+            let config = cli::or_fail(command, lint::parse_config(options.get("-lint").map(|s| s.as_str()).unwrap_or("")));
+            let check = || {
+                let (instructions, _) = assemble_with(&options["-i"], &target.include, &target.defines, align, pic, false);
+                let errors = error_count();
+                let warnings = lint::lint(&instructions, &config) - (error_count() - errors);
+
+                match (error_count(), warnings) {
+                    (0, 0) => println!("{}", "No errors.".bold()),
+                    (0, w) => println!("{}", format!("No errors, {} warning(s).", w).yellow().bold()),
+                    (n, w) => println!("{}", format!("{} error(s), {} warning(s).", n, w).red().bold()),
+                }
+                instructions
+            };
+
+            if options.contains_key("--watch") { watch::watch(&options["-i"], &target.include, check) }
+            check();
+            if error_count() > 0 { std::process::exit(1) }
+        }
+        "lsp" => lsp::serve(align),
+        "fmt" => {
+            let check = options.contains_key("--check");
+            let mut changed = 0;
+
+            let paths = source_files(&options["-i"], &target.include).unwrap_or_else(|e| cli::fail(Some(command), &e));
+            for path in paths.iter().filter(|p| p.extension().is_some_and(|e| e == "asm")) {
+                let source = fs::read_to_string(path).unwrap_or_else(|_| cli::fail(Some(command), &format!("Unable to read `{}`.", path.display())));
+                let formatted = fmt::format(&source);
+                if formatted == source { continue }
+
+                changed += 1;
+                if check {
+                    println!("{} {}", "Would reformat".yellow().bold(), path.display());
+                }
+                else {
+                    fs::write(path, formatted).unwrap();
+                    println!("{} {}", "Formatted".bold(), path.display());
+                }
+            }
+
+            if check && changed > 0 { std::process::exit(1) }
+        }
+        "disasm" => {
+            let bytes = fs::read(&options["-i"]).unwrap_or_else(|_| cli::fail(Some(command), &format!("Unable to read `{}`.", options["-i"])));
+            let text = disasm::disassemble(&bytes, align).unwrap_or_else(|e| cli::fail(Some(command), e));
+
+            match options.get("-o") {
+                Some(path) => fs::write(path, text).unwrap(),
+                None => print!("{}", text),
+            }
+        }
+        "run" => {
+            let (_, bytes) = assemble_with(&options["-i"], &target.include, &target.defines, align, pic, cli::switch(&options, "--optimise"));
+            if error_count() > 0 { std::process::exit(1) }
+
+            let stack = cli::or_fail(command, cli::hex(&options, "-stack")).unwrap_or(align);
+            let ranges = match options.get("-dump") {
+                Some(dump) => {
+                    let range = dump.split_once(":").and_then(|(a, l)| Some((usize::from_str_radix(a, 16).ok()?, usize::from_str_radix(l, 16).ok()?)));
+                    vec![range.unwrap_or_else(|| cli::fail(Some(command), &format!("Expected <address>:<length> in hex for -dump, got `{}`.", dump)))]
+                }
+                None => Vec::new(),
+            };
+            let snapshot_path = options.get("-snapshot");
+            let snapshot_at = cli::or_fail(command, cli::number(&options, "-snapshot-at"));
+
+            let mut machine = sim::Machine::new(&bytes, align, stack);
+            machine.framebuffer = match options.get("-fb") {
+                Some(spec) => Some(fb::Framebuffer::parse(spec).unwrap_or_else(|e| cli::fail(Some(command), e))),
+                None if snapshot_path.is_some() => Some(fb::Framebuffer::default()),
+                None => None,
+            };
+
+            let snapshot = |machine: &sim::Machine| {
+                if let (Some(path), Some(fb)) = (snapshot_path, &machine.framebuffer) {
+                    fb.snapshot(&machine.memory).save(path).expect("Unable to write snapshot.");
+                }
+            };
+
+            let stop = match snapshot_at {
+                Some(at) if at < limit => {
+                    let stop = machine.run(at);
+                    snapshot(&machine);
+                    if stop == sim::Stop::Limit { machine.run(limit) } else { stop }
+                }
+                _ => {
+                    let stop = machine.run(limit);
+                    snapshot(&machine);
+                    stop
+                }
+            };
+
+            match &stop {
+                sim::Stop::Halted =>    println!("{}", "Halted.".bold()),
+                sim::Stop::Limit =>     println!("{} {}", "Stopped:".yellow().bold(), format!("instruction limit of {} reached.", limit).bold()),
+                sim::Stop::Fault(e) =>  println!("{} {}", "Fault:".red().bold(), e.bold()),
+            }
+            print!("{}", machine.dump(&ranges));
+
+            std::process::exit(match stop { sim::Stop::Halted => 0, sim::Stop::Limit => 2, sim::Stop::Fault(_) => 1 });
+        }
+
+        _ => {
+            if options.contains_key("--watch") {
+                if options.contains_key("-drive") { cli::fail(Some(command), "--watch can't be used with -drive.") }
+                let input_path = options.get("-i").unwrap_or_else(|| cli::fail(Some(command), "`asm build --watch` needs -i <input>."));
+
+                watch::watch(input_path, &target.include, || build(command, &options, &target, align));
+            }
 
-        input_path = args[0].clone();
-        output_path = args[1].clone();
-        inter_path = args[3].clone();
-        alignment = args[4].clone();
+            build(command, &options, &target, align);
+            if error_count() > 0 { std::process::exit(1) }
+        }
     }
+}
 
-    let input_path = input_path.expect("Input path must be specified.");
-    let output_path = output_path.expect("Output path must be specified.");
+/// Assembles and writes every requested output, nothing is written when there were errors.
+/// Returns the instructions so `--watch` can find the referenced assets.
+fn build(command: &cli::Command, options: &cli::Options, target: &manifest::Target, align: usize) -> Vec<Line> {
+    let output_path = &options["-o"];
 
-    if let Some(alignment) = alignment {
-        align = usize::from_str_radix(&alignment, 16).expect("Invalid hex literal for alignment.");
+    if let Some(drive_path) = options.get("-drive") {
+        let drive = drive::build_drive(drive_path, &target.include, &target.defines);
+        if error_count() == 0 { fs::write(output_path, drive).unwrap() }
+
+        return Vec::new();
     }
 
-    let paths = get_all_files(input_path.clone());
+    let input_path = options.get("-i").unwrap_or_else(|| cli::fail(Some(command), "`asm build` needs -i <input> or -drive <manifest>."));
+
+    let output_format = match options.get("-format") {
+        Some(name) => format::Format::parse(name).unwrap_or_else(|e| cli::fail(Some(command), e)),
+        None => format::Format::Bin,
+    };
 
-    let mut instructions = lex_files(paths);
+    let inter_data = cli::or_fail(command, cli::number(options, "-inter-data")).unwrap_or(inter::DATA_LEN);
 
     println!("Todo: Alignment, Abstractions, Images");
 
+    let (instructions, bytes) = assemble_with(input_path, &target.include, &target.defines, align, cli::switch(options, "--pic"), cli::switch(options, "--optimise"));
+    if error_count() > 0 {
+        println!("{}", format!("{} error(s), nothing was written.", error_count()).red().bold());
+        return instructions;
+    }
+
+    let symbols = map::collect_symbols(&instructions, align);
+
+    if let Some(path) = options.get("-inter") {
+        inter::write_listing(path.clone(), &instructions, &symbols, align, inter_data);
+    }
+
+    if let Some(path) = options.get("-map") {
+        map::write_map(path.clone(), &symbols, align);
+    }
+
+    if let Some(path) = options.get("-debug") {
+        debug::write_debug_info(path.clone(), &instructions, &symbols, align);
+    }
+
+    if let Some(path) = options.get("-json") {
+        json::write_json(path.clone(), &instructions, &symbols, align);
+    }
+
+    // Embedded in vm64 executables, written next to the output otherwise.
+    let relocations = cli::switch(options, "--reloc").then(|| reloc::table(&reloc::collect(&instructions), align));
+    if let Some(table) = relocations.as_ref().filter(|_| output_format != format::Format::Vm64) {
+        fs::write(format!("{}.reloc", output_path), table).unwrap();
+    }
+
+    fs::write(output_path, format::encode(&output_format, &bytes, &symbols, align, relocations.as_deref())).unwrap();
+    instructions
+}
+
+/// Assembles `input_path` with the include folders. `optimise` runs the peephole optimiser and
+/// prints its report, `pic` then lowers label references to position-independent code.
+fn assemble_with(input_path: &str, include: &[String], defines: &HashMap<String, String>, align: usize, pic: bool, optimise: bool) -> (Vec<Line>, Vec<u8>) {
+    let paths = source_files(input_path, include).unwrap_or_else(|e| {
+        error((Control::None, Vec::new(), String::new(), Vec::new(), input_path.to_owned(), 0), &e);
+        Vec::new()
+    });
+    let mut instructions = testing::strip_tests(lex_files(paths, defines));
+
+    if optimise && error_count() == 0 {
+        let (optimised, changes) = peephole::optimise(instructions);
+        print!("{}", peephole::report(&changes));
+        instructions = optimised;
+    }
+    if pic {
+        instructions = pic::lower(instructions);
+        pic::verify(&instructions);
+    }
+    link(instructions, &asset_dir(input_path), align)
+}
+
+/// Lays lexed instructions out at `align`, loads `#image`/`#bytes` data relative to
+/// `input_path` and patches label and data references.
+fn link(mut instructions: Vec<Line>, input_path: &str, align: usize) -> (Vec<Line>, Vec<u8>) {
     let mut labels: HashMap<String, usize> = HashMap::new();
     let mut data_pointers: HashMap<String, usize> = HashMap::new();
 
@@ -137,11 +320,22 @@ fn main() {
                 labels.insert(instructions[i].2.clone().strip_suffix(":").unwrap().to_owned(), index);
             }
             Control::ImgDataPointer(path) => {
-                let img = ImageReader::open(&format!("{}/{}", &input_path, path)).expect(&format!("Couldn't open {}", path)).decode().unwrap();
+                let img = match ImageReader::open(format!("{}/{}", input_path, path)).map(|r| r.decode()) {
+                    Ok(Ok(img)) => img,
+                    _ => { error(instructions[i].clone(), &format!("Couldn't open image {}.", path)); i += 1; continue }
+                };
+
+                let bytes: Vec<u8> = img.to_rgb8().into_raw();
 
-                let bytes: Vec<u8> = img.as_rgb8().unwrap().clone().into_raw().to_vec();
+                instructions.push((Control::Data, bytes, instructions[i].2.clone(), instructions[i].3.clone(), instructions[i].4.clone(), instructions[i].5));
+            }
+            Control::DataPointer(path) => {
+                let bytes = match fs::read(format!("{}/{}", input_path, path)) {
+                    Ok(bytes) => bytes,
+                    Err(_) => { error(instructions[i].clone(), &format!("Couldn't open {}.", path)); i += 1; continue }
+                };
 
-                instructions.push((Control::Data, bytes, instructions[i].2.clone(), instructions[i].3.clone(), instructions[i].4.clone(), instructions[i].5.clone()));
+                instructions.push((Control::Data, bytes, instructions[i].2.clone(), instructions[i].3.clone(), instructions[i].4.clone(), instructions[i].5));
             }
             Control::Data => {
                 data_pointers.insert(instructions[i].3[0].clone(), index);
@@ -153,13 +347,36 @@ fn main() {
         i += 1;
     }
 
+    let mut index = align;
     for inst in instructions.iter_mut() {
-        if inst.0 == Control::ReqLabel {
-            if let Some(label) = labels.get(inst.3.last().unwrap().as_str()) {
-                let l = inst.1.len();
+        let l = inst.1.len();
 
+        if inst.0 == Control::ReqLabel {
+            let name = inst.3.last().unwrap().as_str();
+            if let Some(label) = labels.get(name).or(data_pointers.get(name)) {
                 inst.1[l-4..].copy_from_slice(&label.to_be_bytes()[4..]);
             }
+            else {
+                error(inst.clone(), "Undefined label.");
+            }
+        }
+        if let Control::ReqRelative(offset) = inst.0 {
+            match labels.get(inst.3[1].as_str()).or(data_pointers.get(inst.3[1].as_str())) {
+                Some(label) => inst.1[l-8..].copy_from_slice(&(*label as u64).wrapping_sub((index + offset) as u64).to_be_bytes()),
+                None => error(inst.clone(), "Undefined label."),
+            }
+        }
+        if let Control::ReqTable(width) = inst.0 {
+            for (n, name) in inst.3.clone().iter().enumerate() {
+                match labels.get(name).or(data_pointers.get(name)) {
+                    Some(address) if width < 8 && *address >> (width * 8) != 0 => error(inst.clone(), &format!("`{}` at 0x{:08x} doesn't fit into {} bytes.", name, address, width)),
+                    Some(address) => inst.1[n * width..(n + 1) * width].copy_from_slice(&(*address as u64).to_be_bytes()[8 - width..]),
+                    None => error(inst.clone(), &format!("Undefined label `{}`.", name)),
+                }
+            }
+        }
+        if let Control::ReqOffset(offset) = inst.0 {
+            inst.1[l-4..].copy_from_slice(&(index + offset).to_be_bytes()[4..]);
         }
         if inst.0 == Control::ReqDataPointer {
             if let Some(pointer) = data_pointers.get(inst.3[1].as_str()) {
@@ -167,6 +384,7 @@ fn main() {
                 inst.1[1..5].copy_from_slice(&pointer.to_be_bytes()[4..]);
             }
         }
+        index += l;
     }
 
     let mut bytes = Vec::new();
@@ -175,56 +393,7 @@ fn main() {
         bytes.append(&mut inst.1.clone());
     }
 
-    if let Some(path) = inter_path {
-        let mut buf = String::new();
-
-        let mut index = align;
-
-        for inst in instructions.iter() {
-            match inst.0 {
-                Control::DataPointer(_) => { continue }
-                Control::ImgDataPointer(_) => { continue }
-                _ => {}
-            }
-
-            let sl = buf.len();
-            if inst.0 != Control::Label { buf.push_str(&format!("0x{:08x}:", index)) }
-
-            while buf.len() < sl + 20 { buf.push(' ') }
-            
-            if inst.0 == Control::Data {
-                for n in inst.1.clone()[..5].into_iter() {
-                    buf.push_str(&format!("{:02x} ", n));
-                }
-                buf.push_str(". . . ");
-                for n in inst.1.clone()[inst.1.len() - 5..].into_iter() {
-                    buf.push_str(&format!("{:02x} ", n));
-                }
-            }
-            else {
-                for n in inst.1.clone().into_iter() {
-                    buf.push_str(&format!("{:02x} ", n));
-                }
-            }
-
-            while buf.len() < sl + 70 { buf.push(' ') }
-
-            buf.push_str(&format!("{} ", inst.2));
-            for arg in inst.3.clone().into_iter() {
-                buf.push_str(&format!("{} ", arg));
-            }
-
-            while buf.len() < sl + 110 { buf.push(' ') }
-
-            buf.push_str(&format!("{}:{}\n", inst.4, inst.5));
-
-            index += inst.1.len();
-        }
-
-        fs::write(path, buf).unwrap();
-    }
-
-    fs::write(output_path, bytes).unwrap();
+    (instructions, bytes)
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -237,88 +406,282 @@ enum Control {
     DataPointer(String),
     ImgDataPointer(String),
     ReqDataPointer,
+    /// Needs its own address plus the offset, like the return address of `call`.
+    ReqOffset(usize),
+    /// Label addresses of a `#table`, each this many bytes.
+    ReqTable(usize),
+    /// Needs the label minus its own address plus the offset, as a full 8 byte immediate.
+    ReqRelative(usize),
+    Test(String),
+    EndTest,
+    Expect(testing::Expect),
 }
 
-fn lex_files(paths: Vec<PathBuf>) -> Vec<Line> {
-    let mut instructions: Vec<Line> = Vec::new();
+fn lex_files(paths: Vec<PathBuf>, defines: &HashMap<String, String>) -> Vec<Line> {
+    let mut sources = Vec::new();
     for path in paths {
-        if path.extension().unwrap() != "asm" { continue }
-        let mut code: Vec<char> = fs::read_to_string(&path).unwrap()
-            .replace("\r", "")
-            .replace("\\\n", " ")
-            .replace(", ", " ")
-            .replace(",", " ")
-            .replace("  ", " ")
-            .replace("  ", " ")
-            .replace("  ", " ")
-            .replace("  ", " ")
-            .replace("0x", "&")
-            .trim()
-            .chars().collect();
-
-        let mut comment = false;
-        let mut i = 0;
-        while i < code.len() {
-            if code[i] == ';' { comment = true }
-            if code[i] == '\n' { comment = false }
-            if comment { code.remove(i); }
-            if !comment { i += 1 }
+        if path.extension().is_none_or(|e| e != "asm") { continue }
+        match fs::read_to_string(&path) {
+            Ok(source) => sources.push((path.display().to_string(), source)),
+            Err(_) => error((Control::None, Vec::new(), String::new(), Vec::new(), path.display().to_string(), 0), "Unable to read file."),
         }
+    }
 
-        let code = code.into_iter().collect::<String>();
+    let mut instructions: Vec<Line> = Vec::new();
+    for (path, source) in preprocess(sources, defines) {
+        instructions.append(&mut lex_source(&source, &path));
+    }
+    instructions
+}
 
-        let lines = code.split("\n").map(|s| s.trim().to_owned()).collect::<Vec<String>>();
+/// Runs the passes over the source text of all files, as (path, source), in order: conditional
+/// assembly, `#import`, struct layouts and instances, then defines. Line numbers stay the same.
+fn preprocess(sources: Vec<(String, String)>, defines: &HashMap<String, String>) -> Vec<(String, String)> {
+    let sources = sources.into_iter().map(|(path, source)| {
+        let source = devices::apply(&conditional::strip_disabled(&source, &path, defines), &path);
+        (path, source)
+    }).collect::<Vec<(String, String)>>();
 
-        for line in 0..lines.len() {
-            if lines[line].is_empty() { continue }
-            let parts = lines[line].split(" ").collect::<Vec<&str>>();
+    let layouts = structs::collect(&sources, defines);
 
-            instructions.push((
-                Control::None,
-                Vec::new(), 
-                parts[0].to_owned(), 
-                parts[1..].into_iter().map(|s| s.to_owned().to_owned()).collect(), 
-                path.display().to_string(), 
-                line + 1
-            ));
+    sources.into_iter().map(|(path, source)| {
+        let source = apply_defines(&structs::apply(&source, &path, &layouts, defines), defines);
+        (path, source)
+    }).collect()
+}
 
-            let lline = instructions.last().unwrap().to_owned();
+/// Splits one line of code, without its comment, into the mnemonic and its arguments.
+fn tokenize(code: &str) -> Vec<String> {
+    code.replace(",", " ").replace("0x", "&").split_whitespace().map(|s| s.to_owned()).collect()
+}
 
-            if lline.2.ends_with(":") {
-                instructions.last_mut().unwrap().0 = Control::Label;
-            }
-            else if lline.2.starts_with("#") {
-                let cmd = lline.2.strip_prefix("#").unwrap();
+/// Replaces every word that names a define with its value.
+fn apply_defines(source: &str, defines: &HashMap<String, String>) -> String {
+    if defines.is_empty() { return source.to_owned() }
 
-                match cmd.to_lowercase().as_str() {
-                    "image" => { instructions.last_mut().unwrap().0 = Control::ImgDataPointer(parts[2].to_owned().to_owned()) }
-                    "bytes" => { instructions.last_mut().unwrap().0 = Control::DataPointer(parts[2].to_owned().to_owned()) }
+    replace_words(source, "_.", defines)
+}
 
-                    _ => { error(lline.clone(), "Unknown assembler command.") }
-                }
-            }
-            else {
-                let mut args: Vec<Arg> = Vec::new();
+/// Replaces whole words found in `words`, a word is made of ASCII letters, digits and `chars`.
+pub fn replace_words(text: &str, chars: &str, words: &HashMap<String, String>) -> String {
+    let mut buf = String::new();
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once('\n')) {
+        if c.is_ascii_alphanumeric() || chars.contains(c) { word.push(c); continue }
+
+        buf.push_str(words.get(&word).unwrap_or(&word));
+        word.clear();
+        buf.push(c);
+    }
+    buf.pop();
+    buf
+}
+
+fn lex_source(source: &str, path: &str) -> Vec<Line> {
+    let mut instructions: Vec<Line> = Vec::new();
+    let mut code: Vec<char> = source
+        .replace("\r", "")
+        .replace("\\\n", " ")
+        .replace(", ", " ")
+        .replace(",", " ")
+        .replace("  ", " ")
+        .replace("  ", " ")
+        .replace("  ", " ")
+        .replace("  ", " ")
+        .replace("0x", "&")
+        .trim_end()
+        .chars().collect();
+
+    let mut comment = false;
+    let mut i = 0;
+    while i < code.len() {
+        if code[i] == ';' { comment = true }
+        if code[i] == '\n' { comment = false }
+        if comment { code.remove(i); }
+        if !comment { i += 1 }
+    }
+
+    let code = code.into_iter().collect::<String>();
+
+    let lines = code.split("\n").enumerate().map(|(i, s)| (i + 1, s.trim().to_owned())).collect::<Vec<repeat::SourceLine>>();
+
+    let mut flow = flow::Flow::default();
+    for (line, text) in repeat::expand(&lines, path).iter() {
+        if text.is_empty() { continue }
+        let parts = tokenize(text);
 
-                for arg in parts[1..].into_iter() {
-                    let arg = resolve_arg(arg.to_owned().to_owned());
+        instructions.push((
+            Control::None,
+            Vec::new(), 
+            parts[0].to_owned(), 
+            parts[1..].iter().map(|s| s.to_owned().to_owned()).collect(), 
+            path.to_owned(), 
+            *line
+        ));
 
-                    match arg {
-                        Ok(arg) => args.push(arg),
+        let lline = instructions.last().unwrap().to_owned();
+
+        if lline.2.ends_with(":") {
+            instructions.last_mut().unwrap().0 = Control::Label;
+            if lline.2.starts_with(flow::HIDDEN) { error(lline.clone(), "Labels starting with `@` are reserved for the assembler.") }
+        }
+        else if let Some(lowered) = flow.lower(&lline) {
+            instructions.pop();
+            match lowered {
+                Ok(lines) => instructions.extend(lines),
+                Err(e) => error(lline.clone(), e),
+            }
+        }
+        else if lline.2.starts_with("#") {
+            let cmd = lline.2.strip_prefix("#").unwrap();
+
+            match cmd.to_lowercase().as_str() {
+                "image" if parts.len() > 2 => { instructions.last_mut().unwrap().0 = Control::ImgDataPointer(parts[2].to_owned().to_owned()) }
+                "bytes" if parts.len() > 2 => { instructions.last_mut().unwrap().0 = Control::DataPointer(parts[2].to_owned().to_owned()) }
+                "image" | "bytes" => { error(lline.clone(), "Invalid number of arguments.") }
+                "test" => {
+                    match parts.get(1) {
+                        Some(name) => instructions.last_mut().unwrap().0 = Control::Test(name.to_string()),
+                        None => error(lline.clone(), "Expected a test name."),
+                    }
+                }
+                "endtest" => { instructions.last_mut().unwrap().0 = Control::EndTest }
+                "expect" => {
+                    match testing::parse_expect(&lline.3) {
+                        Ok(expect) => instructions.last_mut().unwrap().0 = Control::Expect(expect),
+                        Err(e) => error(lline.clone(), e),
+                    }
+                }
+                "instance" => {
+                    // `#instance <name> <bytes>`, as left by `structs::apply`.
+                    instructions.pop();
+                    match generate(&lline, "db", &lline.3[1..].iter().map(|b| b.as_str()).collect::<Vec<&str>>()) {
+                        Ok(data) => instructions.extend([(Control::Label, Vec::new(), format!("{}:", lline.3[0]), Vec::new(), lline.4.clone(), lline.5), data]),
+                        Err(e) => error(lline.clone(), e),
+                    }
+                }
+                "table" => {
+                    instructions.pop();
+                    match table(&lline) {
+                        Ok(lines) => instructions.extend(lines),
                         Err(e) => error(lline.clone(), e),
                     }
                 }
 
-                match resolve_inst(lline.2.clone(), args) {
-                    Ok(res) => { instructions.last_mut().unwrap().0 = res.1; instructions.last_mut().unwrap().1 = res.0 },
+                _ => { error(lline.clone(), "Unknown assembler command.") }
+            }
+        }
+        else if PSEUDO.contains(&lline.2.to_lowercase().as_str()) {
+            match expand_pseudo(&lline) {
+                Ok(expansion) => { instructions.pop(); instructions.extend(expansion) }
+                Err(e) => error(lline.clone(), e),
+            }
+        }
+        else {
+            let mut args: Vec<Arg> = Vec::new();
+
+            for arg in parts[1..].iter() {
+                let arg = resolve_arg(arg.to_owned().to_owned());
+
+                match arg {
+                    Ok(arg) => args.push(arg),
                     Err(e) => error(lline.clone(), e),
                 }
             }
+
+            match resolve_inst(lline.2.clone(), args) {
+                Ok(res) => { instructions.last_mut().unwrap().0 = res.1; instructions.last_mut().unwrap().1 = res.0 },
+                Err(e) => error(lline.clone(), e),
+            }
         }
     }
+
+    for (line, e) in flow.unclosed() {
+        error(line, e);
+    }
+
     instructions
 }
 
+/// Encodes an instruction the assembler writes itself, at the source location of `line`.
+fn generate(line: &Line, mnemonic: &str, args: &[&str]) -> Result<Line, &'static str> {
+    let resolved = args.iter().map(|a| resolve_arg(a.to_string())).collect::<Result<Vec<Arg>, &str>>()?;
+    let (bytes, control) = resolve_inst(mnemonic.to_owned(), resolved)?;
+
+    Ok((control, bytes, mnemonic.to_owned(), args.iter().map(|a| a.to_string()).collect(), line.4.clone(), line.5))
+}
+
+/// Pseudo-instructions, expanded into real ones while lexing.
+const PSEUDO: &[&str] = &["call", "ret", "la", "syscall"];
+
+/// Holds the return address between `call` and `ret`, anything in it is lost on a `call`.
+const LINK_REG: &str = "rff";
+
+/// Expands a pseudo-instruction into lines at the same source location, so listings show
+/// what it became.
+///
+///     call target  ->  mov rff $+17, psh rff, jmp target
+///     ret          ->  pop rff, jmp rff
+///     la rX label  ->  mov rX label
+///     syscall n    ->  mov r0 n, syscall
+fn expand_pseudo(line: &Line) -> Result<Vec<Line>, &'static str> {
+    let expand = |mnemonic: &str, args: &[&str]| generate(line, mnemonic, args);
+    let args = line.3.iter().map(|a| a.as_str()).collect::<Vec<&str>>();
+
+    match (line.2.to_lowercase().as_str(), args.as_slice()) {
+        ("call", [target]) => {
+            let mut lines = vec![expand("mov", &[LINK_REG, "0"])?, expand("psh", &[LINK_REG])?, expand("jmp", &[target])?];
+            let offset = lines.iter().map(|l| l.1.len()).sum();
+
+            lines[0].0 = Control::ReqOffset(offset);
+            lines[0].3[1] = format!("$+{}", offset);
+            Ok(lines)
+        }
+        ("ret", []) => Ok(vec![expand("pop", &[LINK_REG])?, expand("jmp", &[LINK_REG])?]),
+        ("la", [reg, label]) => {
+            if !matches!(resolve_arg(reg.to_string())?, Arg::Ureg(_)) { return Err("Invalid argument, expected register.") }
+            if !matches!(resolve_arg(label.to_string())?, Arg::Label(_)) { return Err("Invalid argument, expected label.") }
+
+            let mut line = expand("mov", &[reg, "0"])?;
+            line.0 = Control::ReqLabel;
+            line.3[1] = label.to_string();
+            Ok(vec![line])
+        }
+        ("syscall", []) => Ok(vec![expand("syscall", &[])?]),
+        ("syscall", [number]) => {
+            if !matches!(resolve_arg(number.to_string())?, Arg::Ureg(_) | Arg::Liter(_)) { return Err("Invalid argument, expected literal or register.") }
+            Ok(vec![expand("mov", &[devices::SYSCALL_REG, number])?, expand("syscall", &[])?])
+        }
+
+        _ => Err("Invalid number of arguments."),
+    }
+}
+
+/// Bytes per `#table` entry when no width is given.
+const TABLE_WIDTH: usize = 4;
+
+/// `#table <name> [width] <label> ...` as the label `name` followed by the label addresses,
+/// which the linker fills in.
+fn table(line: &Line) -> Result<Vec<Line>, &'static str> {
+    let (name, rest) = line.3.split_first().ok_or("Expected `#table <name> [width] <label> ...`.")?;
+    let (width, labels) = match rest.split_first() {
+        Some((width, labels)) if width.starts_with(|c: char| c.is_ascii_digit()) => (width.parse::<usize>().map_err(|_| "Invalid table width.")?, labels),
+
+        _ => (TABLE_WIDTH, rest),
+    };
+
+    if ![2, 4, 8].contains(&width) { return Err("Table entries are 2, 4 or 8 bytes.") }
+    if labels.is_empty() { return Err("Expected `#table <name> [width] <label> ...`.") }
+    for label in labels.iter() {
+        if !matches!(resolve_arg(label.clone())?, Arg::Label(_)) { return Err("Invalid argument, expected label.") }
+    }
+
+    Ok(vec![
+        (Control::Label, Vec::new(), format!("{}:", name), Vec::new(), line.4.clone(), line.5),
+        (Control::ReqTable(width), vec![0; width * labels.len()], line.2.clone(), labels.to_vec(), line.4.clone(), line.5),
+    ])
+}
+
 enum Arg {
     Freg(u8),
     Ureg(u8),
@@ -345,8 +708,14 @@ fn resolve_arg(mut arg: String) -> Result<Arg, &'static str> {
             Err(_) => Err("Invalid register index."),
         }
     }
-    else if arg.starts_with("&") {
-        let n = u64::from_str_radix(&arg[1..], 16);
+    else if arg.starts_with(|c: char| c.is_ascii_digit() || "&(-".contains(c)) && arg.contains(|c: char| "+-*/%()".contains(c)) {
+        match expr::constant(&arg) {
+            Ok(n) => Ok(Arg::Liter((n as u64).to_be_bytes().to_vec())),
+            Err(_) => Err("Invalid expression."),
+        }
+    }
+    else if let Some(hex) = arg.strip_prefix("&") {
+        let n = u64::from_str_radix(hex, 16);
 
         match n {
             Ok(n) => Ok(Arg::Liter(n.to_be_bytes().to_vec())),
@@ -370,6 +739,18 @@ fn resolve_inst(inst: String, args: Vec<Arg>) -> Result<(Vec<u8>, Control), &'st
     let rl = Control::ReqLabel;
     let rd = Control::ReqDataPointer;
 
+    let min_args = match inst.to_lowercase().as_str() {
+        "grapcpy" =>                                                                            6,
+        "add" | "sub" | "mul" | "div" | "mod" | "shl" | "shr" | "and" | "or" | "xor" |
+        "jlg" | "jpe" | "jne" | "memcpy" =>                                                     3,
+        "not" | "out" | "in" =>                                                                 2,
+        "inc" | "dec" | "psh" | "pop" | "adc" | "sbc" | "jmp" | "jpc" | "jnc" | "wit" |
+        "gst" | "gpc" =>                                                                        1,
+
+        _ => 0,
+    };
+    if args.len() < min_args { return Err("Invalid number of arguments.") }
+
 
     match inst.to_lowercase().as_ref() {
         "nop" => { Ok((vec![0x00], ci)) }
@@ -387,8 +768,8 @@ fn resolve_inst(inst: String, args: Vec<Arg>) -> Result<(Vec<u8>, Control), &'st
                     match &args[1] {
                         Arg::Ureg(n) =>        { if float {return Ok((vec![0x03, *dest_reg, *n], ci))} Ok((vec![0x01, *dest_reg, *n], ci)) }
                         Arg::Freg(n) =>        { if float {return Ok((vec![0x02, *dest_reg, *n], ci))} Ok((vec![0x04, *dest_reg, *n], ci)) }  
-                        Arg::Liter(n) =>  { if float {let mut b = vec![0x06, *dest_reg]; b.extend_from_slice(&n); return Ok((b, ci))} let mut b = vec![0x05, *dest_reg]; b.extend_from_slice(&n); Ok((b, ci)) }
-                        Arg::Label(_) =>            { Err("Invalid argument, expected floating point register, literal, or register, got label.") }
+                        Arg::Liter(n) =>  { if float {let mut b = vec![0x06, *dest_reg]; b.extend_from_slice(n); return Ok((b, ci))} let mut b = vec![0x05, *dest_reg]; b.extend_from_slice(n); Ok((b, ci)) }
+                        Arg::Label(_) =>            { Err("Invalid argument, expected floating point register, literal, or register, got label, load addresses with `la`.") }
                     }
                 }
                 3 => {
@@ -867,7 +1248,10 @@ fn resolve_inst(inst: String, args: Vec<Arg>) -> Result<(Vec<u8>, Control), &'st
             }
         }
         
-        "syscall" => { Ok((vec![0x80], ci)) }
+        "syscall" => {
+            if !args.is_empty() { return Err("Invalid number of arguments.") }
+            Ok((vec![0x80], ci))
+        }
         "sysret" =>  { Ok((vec![0x81], ci)) }
         "memcpy" => {
             match (&args[0], &args[1], &args[2]) {
@@ -935,7 +1319,52 @@ fn resolve_inst(inst: String, args: Vec<Arg>) -> Result<(Vec<u8>, Control), &'st
     }
 }
 
+/// Number of errors reported so far, nothing is written when it isn't zero.
+static ERRORS: AtomicUsize = AtomicUsize::new(0);
+
+fn error_count() -> usize {
+    ERRORS.load(Ordering::Relaxed)
+}
+
+fn reset_errors() {
+    ERRORS.store(0, Ordering::Relaxed);
+}
+
+/// A collected diagnostic, the flag is set for errors and clear for warnings.
+type Diagnostic = (Line, String, bool);
+
+thread_local! {
+    /// When set, `error()` and `warning()` collect into this list instead of printing.
+    static DIAGNOSTICS: RefCell<Option<Vec<Diagnostic>>> = const { RefCell::new(None) };
+}
+
+/// Runs `f` and returns the diagnostics it reported instead of printing them.
+fn collect_errors<T>(f: impl FnOnce() -> T) -> (T, Vec<Diagnostic>) {
+    DIAGNOSTICS.with(|d| *d.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    (result, DIAGNOSTICS.with(|d| d.borrow_mut().take().unwrap()))
+}
+
 fn error(line: Line, error: &str) {
+    ERRORS.fetch_add(1, Ordering::Relaxed);
+
+    let collected = DIAGNOSTICS.with(|d| d.borrow_mut().as_mut().map(|list| list.push((line.clone(), error.to_owned(), true))).is_some());
+    if collected { return }
+
+    print_diagnostic("Error".red().bold(), line, error);
+
+    //process::exit(0);
+}
+
+/// Like `error()`, but doesn't stop the output from being written.
+fn warning(line: Line, warning: &str) {
+    let collected = DIAGNOSTICS.with(|d| d.borrow_mut().as_mut().map(|list| list.push((line.clone(), warning.to_owned(), false))).is_some());
+    if collected { return }
+
+    print_diagnostic("Warning".yellow().bold(), line, warning);
+}
+
+fn print_diagnostic(kind: colored::ColoredString, line: Line, message: &str) {
     let form_err = format!(indoc! {"
         
         {}: {}
@@ -944,7 +1373,7 @@ fn error(line: Line, error: &str) {
         {  } {} {} {}
              {}
     "}, 
-    "Error".red().bold(), error.bold(),
+    kind, message.bold(),
     "-->".bright_cyan().bold(), line.4, line.5,
     "|".bright_cyan().bold(), 
     format!("{:4}", line.5).bright_cyan().bold(), "|".bright_cyan().bold(), line.2, line.3.join(" "),
@@ -952,8 +1381,6 @@ fn error(line: Line, error: &str) {
     );
 
     println!("{}", form_err);
-
-    //process::exit(0);
 }
 
 
@@ -973,6 +1400,11 @@ mod tests {
         }
     }
 
+    /// A `main.asm` line for tests that build instructions by hand.
+    pub fn line(control: Control, bytes: Vec<u8>, inst: &str, args: &[&str], line: usize) -> Line {
+        (control, bytes, inst.to_owned(), args.iter().map(|s| s.to_string()).collect(), String::from("main.asm"), line)
+    }
+
     #[test]
     fn nop_00() {
         assert_eq!(resolve_inst(String::from("nop"), Arg::new("")).unwrap().0, vec![0x00]);
@@ -1187,4 +1619,88 @@ mod tests {
     fn mov_2a() {
         assert_eq!(resolve_inst(String::from("mvd"), Arg::new("f0 2 &1234 r1")).unwrap().0, vec![0x2A, 0x00, 0x02, 0x01, 0x00, 0x00, 0x12, 0x34]);
     }
+
+    #[test]
+    fn add_too_few_args() {
+        assert_eq!(resolve_inst(String::from("add"), Arg::new("r0 r1")).unwrap_err(), "Invalid number of arguments.");
+    }
+
+    #[test]
+    fn jpe_too_few_args() {
+        assert_eq!(resolve_inst(String::from("jpe"), Arg::new("r0 r1")).unwrap_err(), "Invalid number of arguments.");
+    }
+
+    #[test]
+    fn inc_too_few_args() {
+        assert_eq!(resolve_inst(String::from("inc"), Vec::new()).unwrap_err(), "Invalid number of arguments.");
+    }
+
+    #[test]
+    fn syscall_args() {
+        assert_eq!(resolve_inst(String::from("syscall"), Vec::new()).unwrap().0, vec![0x80]);
+        assert_eq!(resolve_inst(String::from("syscall"), Arg::new("r1")).unwrap_err(), "Invalid number of arguments.");
+    }
+
+    #[test]
+    fn call_ret_la() {
+        let source = indoc! {"
+            la r1 value
+            call double
+            call double
+            hlt
+            double:
+            add r1 r1 r1
+            ret
+            value:
+        "};
+        let (instructions, bytes) = link(lex_source(source, "test.asm"), ".", 0x1000);
+        assert_eq!(instructions.iter().filter(|l| l.5 == 2).map(|l| l.2.as_str()).collect::<Vec<&str>>(), vec!["mov", "psh", "jmp"]);
+        assert_eq!(instructions[1].3, vec!["rff", "$+17"]);
+
+        let mut machine = sim::Machine::new(&bytes, 0x1000, 0x8000);
+        assert!(matches!(machine.run(100), sim::Stop::Halted));
+        assert_eq!((machine.regs[1], machine.sp), (4 * (0x1000 + bytes.len() as u64), 0x8000));
+        let (_, errors) = collect_errors(|| lex_source("la f1 value\nla r1 2\nret r1\n", "test.asm"));
+        assert_eq!(errors.iter().map(|e| e.1.as_str()).collect::<Vec<&str>>(), vec!["Invalid argument, expected register.", "Invalid argument, expected label.", "Invalid number of arguments."]);
+    }
+
+    #[test]
+    fn defines_in_expressions() {
+        let defines = HashMap::from([(String::from("SIZE"), String::from("4"))]);
+        assert_eq!(apply_defines("db SIZE*2\nmov r0 SIZE;note\nmov\tr1\tSIZE\nmov r2 SIZE_2", &defines), "db 4*2\nmov r0 4;note\nmov\tr1\t4\nmov r2 SIZE_2");
+    }
+
+    #[test]
+    fn jump_table() {
+        let source = indoc! {"
+            la r1 states
+            mva r2 4 4 r1
+            jmp r2
+            first:
+            hlt
+            second:
+            inc r3
+            hlt
+            #table states first second
+            #table short 2 second
+        "};
+        let (instructions, bytes) = link(lex_source(source, "test.asm"), ".", 0x1000);
+        let table = |name: &str| instructions.iter().skip_while(|l| l.2 != format!("{}:", name)).nth(1).unwrap().1.clone();
+        assert_eq!((table("states"), table("short")), (vec![0, 0, 0x10, 0x14, 0, 0, 0x10, 0x15], vec![0x10, 0x15]));
+
+        let mut machine = sim::Machine::new(&bytes, 0x1000, 0x8000);
+        assert!(matches!(machine.run(100), sim::Stop::Halted));
+        assert_eq!(machine.regs[3], 1);
+
+        let (_, errors) = collect_errors(|| link(lex_source("#table a 3 b\n#table c\n#table d 2 e\n", "test.asm"), ".", 0x10000));
+        assert_eq!(errors.iter().map(|e| e.1.as_str()).collect::<Vec<&str>>(), vec!["Table entries are 2, 4 or 8 bytes.", "Expected `#table <name> [width] <label> ...`.", "Undefined label `e`."]);
+    }
+
+    #[test]
+    fn line_numbers_after_disabled_block() {
+        let sources = vec![(String::from("main.asm"), String::from("#ifdef X\nnop\n#endif\nfoo r1\n"))];
+
+        let (_, errors) = collect_errors(|| preprocess(sources, &HashMap::new()).into_iter().flat_map(|(path, source)| lex_source(&source, &path)).collect::<Vec<Line>>());
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<(usize, &str)>>(), vec![(4, "Invalid instruction.")]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,194 @@
+use std::{collections::HashMap, fs};
+
+use toml::{Table, Value};
+
+use crate::cli::Options;
+
+/// Manifest picked up from the working directory when no input is given.
+pub const MANIFEST: &str = "vm64.toml";
+
+pub const DEFAULT_PROFILE: &str = "debug";
+
+/// Manifest keys and the flag each one sets.
+const KEYS: &[(&str, &str)] = &[
+    ("entry",   "-i"),
+    ("output",  "-o"),
+    ("base",    "-align"),
+    ("format",  "-format"),
+    ("drive",   "-drive"),
+    ("inter",   "-inter"),
+    ("map",     "-map"),
+    ("debug",   "-debug"),
+    ("json",    "-json"),
+    ("stack",   "-stack"),
+    ("limit",   "-limit"),
+    ("lint",    "-lint"),
+    ("optimise", "--optimise"),
+    ("pic",     "--pic"),
+    ("reloc",   "--reloc"),
+];
+
+/// Hex keys, integers are written back as hex so they read like the flags.
+const HEX_KEYS: &[&str] = &["base", "stack"];
+
+/// A resolved target, its flags plus what can't be said with one flag.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Target {
+    pub name: String,
+    pub options: Options,
+    pub defines: HashMap<String, String>,
+    pub include: Vec<String>,
+}
+
+fn string(value: &Value, key: &str, hex: bool) -> Result<String, String> {
+    match value {
+        Value::String(s) =>                 Ok(s.clone()),
+        Value::Integer(n) if hex =>         Ok(format!("{:x}", n)),
+        Value::Integer(n) =>                Ok(n.to_string()),
+        Value::Boolean(b) =>                Ok(b.to_string()),
+
+        _ => Err(format!("`{}` expects a string or a number.", key)),
+    }
+}
+
+/// Applies one table of target keys on top of `target`. Nested tables named like one of
+/// `profiles` are skipped, they're applied separately.
+fn apply(target: &mut Target, table: &Table, context: &str, profiles: &[&str]) -> Result<(), String> {
+    for (key, value) in table.iter() {
+        match key.as_str() {
+            "defines" => {
+                let defines = value.as_table().ok_or_else(|| format!("`defines` in {} expects a table.", context))?;
+                for (name, value) in defines.iter() {
+                    target.defines.insert(name.clone(), string(value, name, false).map_err(|e| format!("{} in {}", e, context))?);
+                }
+            }
+            "include" => {
+                let include = value.as_array().ok_or_else(|| format!("`include` in {} expects a list of folders.", context))?;
+                for path in include.iter() {
+                    target.include.push(path.as_str().ok_or_else(|| format!("`include` in {} expects a list of folders.", context))?.to_owned());
+                }
+            }
+
+            _ if value.is_table() && profiles.contains(&key.as_str()) => {}
+            _ => {
+                let (_, flag) = KEYS.iter().find(|(k, _)| k == key).ok_or_else(|| format!("Unknown key `{}` in {}.", key, context))?;
+                target.options.insert(flag, string(value, key, HEX_KEYS.contains(&key.as_str())).map_err(|e| format!("{} in {}", e, context))?);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves `name`, or the default target, from a manifest like
+///
+///     default = "game"
+///
+///     [profile.release]
+///     format = "vm64"
+///
+///     [target.game]
+///     entry = "game"
+///     output = "game.bin"
+///     base = 0x00C00000
+///     include = ["lib"]
+///     defines = { LIVES = "3" }
+///
+///     [target.game.release]
+///     output = "game.exe"
+///
+/// Later layers win: the target, then `[profile.<profile>]`, then `[target.<name>.<profile>]`.
+pub fn load(path: &str, name: Option<&str>, profile: &str) -> Result<Target, String> {
+    let text = fs::read_to_string(path).map_err(|_| format!("Unable to read manifest `{}`.", path))?;
+    parse(&text, path, name, profile)
+}
+
+pub fn parse(text: &str, path: &str, name: Option<&str>, profile: &str) -> Result<Target, String> {
+    let manifest: Table = text.parse().map_err(|e: toml::de::Error| format!("`{}`: {}", path, e.message()))?;
+
+    for key in manifest.keys() {
+        if !["default", "profile", "target"].contains(&key.as_str()) {
+            return Err(format!("Unknown key `{}` in `{}`.", key, path));
+        }
+    }
+
+    let empty = Table::new();
+    let targets = manifest.get("target").and_then(|t| t.as_table()).ok_or_else(|| format!("`{}` has no [target.<name>] tables.", path))?;
+    let profiles = manifest.get("profile").and_then(|t| t.as_table()).unwrap_or(&empty);
+
+    let name = match (name, manifest.get("default").and_then(|d| d.as_str())) {
+        (Some(name), _) | (None, Some(name)) => name.to_owned(),
+        (None, None) if targets.len() == 1 => targets.keys().next().unwrap().clone(),
+        (None, None) => return Err(format!("`{}` has several targets, pick one with -target or set `default`.", path)),
+    };
+
+    let table = targets.get(&name).and_then(|t| t.as_table())
+        .ok_or_else(|| format!("No target `{}` in `{}`, expected one of {}.", name, path, targets.keys().cloned().collect::<Vec<String>>().join(", ")))?;
+
+    if profile != "debug" && profile != "release" && !profiles.contains_key(profile) {
+        return Err(format!("Unknown profile `{}`, expected debug, release or one from [profile].", profile));
+    }
+
+    let known = ["debug", "release"].into_iter().chain(profiles.keys().map(|p| p.as_str())).collect::<Vec<&str>>();
+
+    let mut target = Target { name: name.clone(), ..Target::default() };
+    apply(&mut target, table, &format!("[target.{}]", name), &known)?;
+
+    if let Some(layer) = profiles.get(profile) {
+        apply(&mut target, layer.as_table().ok_or_else(|| format!("[profile.{}] must be a table.", profile))?, &format!("[profile.{}]", profile), &[])?;
+    }
+    if let Some(layer) = table.get(profile).and_then(|t| t.as_table()) {
+        apply(&mut target, layer, &format!("[target.{}.{}]", name, profile), &[])?;
+    }
+
+    Ok(target)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MANIFEST: &str = indoc::indoc! {r#"
+        default = "game"
+
+        [profile.release]
+        format = "vm64"
+        defines = { DEBUG = "0" }
+
+        [target.game]
+        entry = "game"
+        output = "game.bin"
+        base = 0x00C00000
+        include = ["lib"]
+        defines = { DEBUG = "1", LIVES = "3" }
+
+        [target.game.release]
+        output = "game.exe"
+
+        [target.tools]
+        entry = "tools/main.asm"
+        output = "tools.bin"
+    "#};
+
+    #[test]
+    fn profiles_layer_on_targets() {
+        let debug = parse(MANIFEST, "vm64.toml", None, "debug").unwrap();
+        assert_eq!((debug.options["-i"].as_str(), debug.options["-o"].as_str(), debug.options["-align"].as_str()), ("game", "game.bin", "c00000"));
+        assert_eq!((debug.defines["DEBUG"].as_str(), debug.include.clone()), ("1", vec![String::from("lib")]));
+        assert!(!debug.options.contains_key("-format"));
+
+        let release = parse(MANIFEST, "vm64.toml", Some("game"), "release").unwrap();
+        assert_eq!((release.options["-o"].as_str(), release.options["-format"].as_str()), ("game.exe", "vm64"));
+        assert_eq!((release.defines["DEBUG"].as_str(), release.defines["LIVES"].as_str()), ("0", "3"));
+    }
+
+    #[test]
+    fn manifest_errors() {
+        assert!(parse(MANIFEST, "vm64.toml", Some("nope"), "debug").unwrap_err().contains("expected one of game, tools"));
+        assert!(parse(MANIFEST, "vm64.toml", None, "fast").is_err());
+        assert!(parse("[target.a]\nentyr = \"a\"", "vm64.toml", None, "debug").unwrap_err().contains("Unknown key `entyr`"));
+        assert_eq!(parse("[target.a.relase]\noutput = \"a\"", "vm64.toml", None, "debug").unwrap_err(), "Unknown key `relase` in [target.a].");
+        assert_eq!(parse("[target.a.release.x]", "vm64.toml", None, "release").unwrap_err(), "Unknown key `x` in [target.a.release].");
+    }
+}
@@ -0,0 +1,145 @@
+use std::fs;
+
+use crate::{flow, Control, Line};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SymbolKind {
+    Section,
+    Label,
+    DataPointer,
+    Image,
+}
+
+impl SymbolKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            SymbolKind::Section =>      "section",
+            SymbolKind::Label =>        "label",
+            SymbolKind::DataPointer =>  "bytes",
+            SymbolKind::Image =>        "image",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Symbol {
+    pub kind: SymbolKind,
+    pub name: String,
+    pub address: usize,
+    pub size: usize,
+    pub file: String,
+    pub line: usize,
+}
+
+/// Walks the laid out instructions and returns every section, label, data pointer and image
+/// with its absolute address, sorted by address.
+pub fn collect_symbols(instructions: &[Line], align: usize) -> Vec<Symbol> {
+    let mut symbols = Vec::new();
+    let mut labels: Vec<Symbol> = Vec::new();
+
+    // Image and byte blobs are appended after all source lines, so everything before the first
+    // `Control::Data` belongs to the code section.
+    let mut text_end = None;
+
+    let mut index = align;
+    for inst in instructions.iter() {
+        match inst.0 {
+            Control::Label if !inst.2.starts_with(flow::HIDDEN) => {
+                labels.push(Symbol {
+                    kind: SymbolKind::Label,
+                    name: inst.2.strip_suffix(":").unwrap_or(&inst.2).to_owned(),
+                    address: index,
+                    size: 0,
+                    file: inst.4.clone(),
+                    line: inst.5,
+                });
+            }
+            Control::Data => {
+                if text_end.is_none() { text_end = Some(index) }
+
+                symbols.push(Symbol {
+                    kind: if inst.2.to_lowercase() == "#image" { SymbolKind::Image } else { SymbolKind::DataPointer },
+                    name: inst.3[0].clone(),
+                    address: index,
+                    size: inst.1.len(),
+                    file: inst.4.clone(),
+                    line: inst.5,
+                });
+            }
+
+            _ => {}
+        }
+        index += inst.1.len();
+    }
+
+    let text_end = text_end.unwrap_or(index);
+
+    // A label spans up to the next label or the end of the code section.
+    for i in 0..labels.len() {
+        let end = labels.get(i + 1).map(|l| l.address).unwrap_or(text_end);
+        labels[i].size = end - labels[i].address;
+    }
+    symbols.append(&mut labels);
+
+    symbols.push(Symbol { kind: SymbolKind::Section, name: String::from(".text"), address: align, size: text_end - align, file: String::new(), line: 0 });
+    if index > text_end {
+        symbols.push(Symbol { kind: SymbolKind::Section, name: String::from(".data"), address: text_end, size: index - text_end, file: String::new(), line: 0 });
+    }
+
+    symbols.sort_by_key(|s| (s.address, s.kind != SymbolKind::Section));
+    symbols
+}
+
+/// Formats the sections and symbols as two tables, shared by the map file and the `-inter` listing.
+pub fn symbol_table(symbols: &[Symbol]) -> String {
+    let name_width = symbols.iter().map(|s| s.name.len()).max().unwrap_or(0).max(4);
+
+    let mut buf = String::from("Sections\n");
+    buf.push_str(&format!("{:10}  {:10}  {}\n", "address", "size", "name"));
+    for s in symbols.iter().filter(|s| s.kind == SymbolKind::Section) {
+        buf.push_str(&format!("0x{:08x}  0x{:08x}  {}\n", s.address, s.size, s.name));
+    }
+
+    buf.push_str("\nSymbols\n");
+    buf.push_str(&format!("{:10}  {:10}  {:7}  {:name_width$}  {}\n", "address", "size", "kind", "name", "source"));
+    for s in symbols.iter().filter(|s| s.kind != SymbolKind::Section) {
+        buf.push_str(&format!("0x{:08x}  0x{:08x}  {:7}  {:name_width$}  {}:{}\n", s.address, s.size, s.kind.name(), s.name, s.file, s.line));
+    }
+
+    buf
+}
+
+pub fn write_map(path: String, symbols: &[Symbol], align: usize) {
+    let mut buf = format!("; base 0x{:08x}\n\n", align);
+    buf.push_str(&symbol_table(symbols));
+
+    fs::write(path, buf).unwrap();
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::line;
+
+    #[test]
+    fn labels_and_images() {
+        let instructions = vec![
+            line(Control::ImgDataPointer(String::from("a.png")), vec![], "#image", &["sprite", "a.png"], 1),
+            line(Control::Label, vec![], "start:", &[], 2),
+            line(Control::Inst, vec![0x00, 0x00], "nop", &[], 3),
+            line(Control::Label, vec![], "loop:", &[], 4),
+            line(Control::ReqLabel, vec![0x50, 0, 0, 0, 0], "jmp", &["loop"], 5),
+            line(Control::Data, vec![1, 2, 3], "#image", &["sprite", "a.png"], 1),
+        ];
+
+        let symbols = collect_symbols(&instructions, 0x100);
+        let find = |name: &str| symbols.iter().find(|s| s.name == name).unwrap().clone();
+
+        assert_eq!((find(".text").address, find(".text").size), (0x100, 7));
+        assert_eq!((find(".data").address, find(".data").size), (0x107, 3));
+        assert_eq!((find("start").address, find("start").size), (0x100, 2));
+        assert_eq!((find("loop").address, find("loop").size, find("loop").line), (0x102, 5, 4));
+        assert_eq!((find("sprite").kind, find("sprite").address), (SymbolKind::Image, 0x107));
+    }
+}
@@ -0,0 +1,172 @@
+//! Opt-in peephole optimiser, `--optimise`, run over the lexed instructions before linking so
+//! labels and relocations are laid out again afterwards:
+//!
+//!     nop                         removed
+//!     mov r1 r1                   removed
+//!     mov r9 1 ... add r2 r2 r9   inc r2, while r9 is known to hold 1
+//!     jmp a ... a: jmp b          jmp b, for every jump to a label
+//!
+//! A register is only known to hold 1 from its `mov` up to the next label, jump or syscall.
+//! Addresses written as numbers, rather than labels, aren't adjusted for removed bytes.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::{generate, lint, Control, Line};
+
+/// (original line, what it became, bytes saved)
+pub type Change = (Line, String, usize);
+
+const JUMPS: &[&str] = &["jmp", "jpc", "jnc", "jpe", "jne", "jlg"];
+
+fn is_inst(line: &Line, mnemonic: &str) -> bool {
+    matches!(line.0, Control::Inst) && line.2.eq_ignore_ascii_case(mnemonic)
+}
+
+fn text(line: &Line) -> String {
+    format!("`{} {}`", line.2, line.3.join(" ")).replace(" `", "`")
+}
+
+/// Where `label` jumps on to when the first instruction after it is an unconditional jump.
+fn forwards<'a>(instructions: &'a [Line], labels: &HashMap<&str, usize>, label: &str) -> Option<&'a str> {
+    let next = instructions[*labels.get(label)?..].iter().find(|l| !l.1.is_empty())?;
+    (next.0 == Control::ReqLabel && next.1[0] == 0x50).then(|| next.3[0].as_str())
+}
+
+/// The last label of a chain of unconditional jumps starting at `label`, `None` when there's
+/// no chain or it loops.
+fn chain_end<'a>(instructions: &'a [Line], labels: &HashMap<&str, usize>, label: &'a str) -> Option<&'a str> {
+    let mut seen = HashSet::from([label]);
+    let mut end = label;
+
+    while let Some(next) = forwards(instructions, labels, end) {
+        if !seen.insert(next) { return None }
+        end = next;
+    }
+
+    (end != label).then_some(end)
+}
+
+/// Optimises `instructions`, returns them with every change made.
+pub fn optimise(instructions: Vec<Line>) -> (Vec<Line>, Vec<Change>) {
+    let labels: HashMap<&str, usize> = instructions.iter().enumerate()
+        .filter(|(_, l)| l.0 == Control::Label)
+        .map(|(i, l)| (l.2.strip_suffix(":").unwrap(), i))
+        .collect();
+
+    let mut optimised = Vec::new();
+    let mut changes: Vec<Change> = Vec::new();
+    let mut ones: HashSet<u8> = HashSet::new();
+
+    for line in instructions.iter() {
+        let bytes = &line.1;
+
+        let replacement = if is_inst(line, "nop") || is_inst(line, "mov") && matches!(bytes[..], [0x01 | 0x02, a, b] if a == b) {
+            Some(Vec::new())
+        }
+        else if is_inst(line, "add") && bytes[0] == 0x30 && (bytes[1] == bytes[2] && ones.contains(&bytes[3]) || bytes[1] == bytes[3] && ones.contains(&bytes[2])) {
+            generate(line, "inc", &[&line.3[0]]).ok().map(|inc| vec![inc])
+        }
+        else if line.0 == Control::ReqLabel && JUMPS.contains(&line.2.to_lowercase().as_str()) {
+            chain_end(&instructions, &labels, line.3.last().unwrap()).and_then(|end| {
+                let args = line.3[..line.3.len() - 1].iter().map(|a| a.as_str()).chain([end]).collect::<Vec<&str>>();
+                generate(line, &line.2, &args).ok().map(|jump| vec![jump])
+            })
+        }
+        else {
+            None
+        };
+
+        let lines = match replacement {
+            Some(lines) => {
+                let size = lines.iter().map(|l| l.1.len()).sum::<usize>();
+                let what = match lines.first() {
+                    Some(new) => format!("{} to {}", text(line), text(new)),
+                    None => format!("removed {}", text(line)),
+                };
+                changes.push((line.clone(), what, line.1.len() - size));
+                lines
+            }
+            None => vec![line.clone()],
+        };
+
+        // Tracks registers holding 1 through the straight line code.
+        for line in lines.iter() {
+            match (&line.0, line.1.first()) {
+                (Control::Label, _) | (_, Some(0x50..=0x61 | 0x70 | 0x80 | 0x81)) => ones.clear(),
+                (Control::Inst, Some(0x05)) if !line.2.eq_ignore_ascii_case("db") => {
+                    if line.1[2..] == 1u64.to_be_bytes() { ones.insert(line.1[1]); } else { ones.remove(&line.1[1]); }
+                }
+                (_, Some(_)) if !line.2.eq_ignore_ascii_case("db") => {
+                    for (float, reg) in lint::registers(&line.1).1 {
+                        if !float { ones.remove(&reg); }
+                    }
+                }
+
+                _ => {}
+            }
+        }
+
+        optimised.extend(lines);
+    }
+
+    (optimised, changes)
+}
+
+/// What changed, one line per change, and the bytes saved.
+pub fn report(changes: &[Change]) -> String {
+    let saved = changes.iter().map(|c| c.2).sum::<usize>();
+    let width = changes.iter().map(|c| format!("{}:{}", c.0.4, c.0.5).len()).max().unwrap_or(0);
+
+    let mut buf = format!("Optimised {} instruction(s), {} bytes saved.\n", changes.len(), saved);
+    for (line, what, _) in changes.iter() {
+        buf.push_str(&format!("    {:width$}  {}\n", format!("{}:{}", line.4, line.5), what, width = width));
+    }
+
+    buf
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex_source, link, sim};
+
+    #[test]
+    fn optimised_program_runs_the_same() {
+        let source = indoc::indoc! {"
+            mov r9 1
+            nop
+            mov r1 r1
+            add r2 r2 r9
+            add r3 r9 r3
+            jmp first
+            hlt
+            first:
+            jmp second
+            second:
+            jmp done
+            done:
+            add r4 r4 r9
+            mov r9 2
+            add r5 r5 r9
+            hlt
+        "};
+        let (instructions, changes) = optimise(lex_source(source, "test.asm"));
+        assert_eq!(report(&changes), indoc::indoc! {"
+            Optimised 6 instruction(s), 8 bytes saved.
+                test.asm:2  removed `nop`
+                test.asm:3  removed `mov r1 r1`
+                test.asm:4  `add r2 r2 r9` to `inc r2`
+                test.asm:5  `add r3 r9 r3` to `inc r3`
+                test.asm:6  `jmp first` to `jmp done`
+                test.asm:9  `jmp second` to `jmp done`
+        "});
+
+        let run = |instructions: Vec<Line>| {
+            let mut machine = sim::Machine::new(&link(instructions, ".", 0x1000).1, 0x1000, 0x8000);
+            assert_eq!(machine.run(100), sim::Stop::Halted);
+            machine.regs
+        };
+        assert_eq!(run(instructions), run(lex_source(source, "test.asm")));
+    }
+}
@@ -0,0 +1,216 @@
+//! Position-independent code, `--pic`. Label references are lowered to offsets from the
+//! program counter, so the same bytes run wherever the program is loaded:
+//!
+//!     la r1 label         ->  mov rfe label-$, gpc r1, add r1 r1 rfe
+//!     jmp label           ->  mov rfe label-$, gpc rff, add rff rff rfe, jmp rff
+//!     jpe r1 r2 label     ->  mov rfe label-$, gpc rff, add rff rff rfe, jpe r1 r2 rff
+//!     call label          ->  the return address like `la`, then the jump
+//!     jpc label           ->  gst rfe, psh rfe, the jump address like `jmp`, pop rfe,
+//!                             dec rfe, not rfe, add rfe rfe rfe, jpc rff
+//!
+//! The `add` changes carry, so `jpc` and `jnc` save it on the stack first and set it again
+//! from the saved value before jumping. The other lowered references leave carry changed.
+//!
+//! `rfe` and `rff` are scratch registers in this mode. References that still need an absolute
+//! address afterwards, like `#table` entries or `grapcpy` from an image, are errors.
+
+use std::collections::HashSet;
+
+use crate::{error, flow::HIDDEN, generate, Control, Line, LINK_REG};
+
+/// Holds the offset of `gpc` to the target.
+pub const SCRATCH: &str = "rfe";
+
+const JUMPS: &[&str] = &["jmp", "jpc", "jnc", "jpe", "jne", "jlg"];
+
+/// Jumps that read carry, which the address calculation changes.
+const CARRY_JUMPS: &[&str] = &["jpc", "jnc"];
+
+fn is_scratch(arg: &str) -> bool {
+    [SCRATCH, LINK_REG].contains(&arg.replace("_", "").to_lowercase().as_str())
+}
+
+/// Loads the address of `label` into `reg` relative to the `gpc` in between.
+fn relative(line: &Line, reg: &str, label: &str) -> Result<Vec<Line>, &'static str> {
+    if reg.eq_ignore_ascii_case(SCRATCH) { return Err("`rfe` is a scratch register with --pic.") }
+
+    let mut offset = generate(line, "mov", &[SCRATCH, "0"])?;
+    let gpc = generate(line, "gpc", &[reg])?;
+
+    offset.0 = Control::ReqRelative(offset.1.len() + gpc.1.len());
+    offset.3[1] = label.to_owned();
+    Ok(vec![offset, gpc, generate(line, "add", &[reg, reg, SCRATCH])?])
+}
+
+/// Saves carry around `lines` and sets it again afterwards: `dec` and `not` turn the saved 1 into
+/// all ones and 0 into 0, adding that to itself carries out exactly when carry was set.
+fn keep_carry(line: &Line, lines: Vec<Line>) -> Result<Vec<Line>, &'static str> {
+    let mut kept = vec![generate(line, "gst", &[SCRATCH])?, generate(line, "psh", &[SCRATCH])?];
+    kept.extend(lines);
+    kept.push(generate(line, "pop", &[SCRATCH])?);
+    kept.push(generate(line, "dec", &[SCRATCH])?);
+    kept.push(generate(line, "not", &[SCRATCH, SCRATCH])?);
+    kept.push(generate(line, "add", &[SCRATCH, SCRATCH, SCRATCH])?);
+    Ok(kept)
+}
+
+fn lower_line(line: &Line, returns: &mut Vec<(usize, String)>, instructions: &[Line], i: usize) -> Result<Vec<Line>, &'static str> {
+    let mnemonic = line.2.to_lowercase();
+
+    match &line.0 {
+        // The return address of `call`, a hidden label goes where it pointed.
+        Control::ReqOffset(offset) => {
+            let (mut size, mut end) = (0, i);
+            while end < instructions.len() && size < *offset {
+                size += instructions[end].1.len();
+                end += 1;
+            }
+
+            let name = format!("{}{}:{}.ret~{}", HIDDEN, line.4, line.5, returns.len());
+            returns.push((end, name.clone()));
+            relative(line, &line.3[0], &name)
+        }
+        Control::ReqLabel if mnemonic == "mov" => relative(line, &line.3[0], &line.3[1]),
+        Control::ReqLabel if JUMPS.contains(&mnemonic.as_str()) => {
+            let (label, operands) = line.3.split_last().unwrap();
+            if operands.iter().any(|a| is_scratch(a)) { return Err("`rfe` and `rff` are scratch registers with --pic.") }
+
+            let mut lines = relative(line, LINK_REG, label)?;
+            if CARRY_JUMPS.contains(&mnemonic.as_str()) { lines = keep_carry(line, lines)? }
+            let args = operands.iter().map(|a| a.as_str()).chain(std::iter::once(LINK_REG)).collect::<Vec<&str>>();
+            lines.push(generate(line, &mnemonic, &args)?);
+            Ok(lines)
+        }
+
+        _ => Ok(vec![line.clone()]),
+    }
+}
+
+/// Lowers every label reference that can be made relative, the rest is left for `verify`.
+pub fn lower(instructions: Vec<Line>) -> Vec<Line> {
+    let mut lowered = Vec::new();
+    let mut returns: Vec<(usize, String)> = Vec::new();
+
+    for (i, line) in instructions.iter().enumerate() {
+        for (_, name) in returns.iter().filter(|(at, _)| *at == i) {
+            lowered.push((Control::Label, Vec::new(), format!("{}:", name), Vec::new(), line.4.clone(), line.5));
+        }
+
+        match lower_line(line, &mut returns, &instructions, i) {
+            Ok(lines) => lowered.extend(lines),
+            Err(e) => { error(line.clone(), e); lowered.push(line.clone()) }
+        }
+    }
+
+    let (file, n) = instructions.last().map(|l| (l.4.clone(), l.5)).unwrap_or_default();
+    for (_, name) in returns.iter().filter(|(at, _)| *at == instructions.len()) {
+        lowered.push((Control::Label, Vec::new(), format!("{}:", name), Vec::new(), file.clone(), n));
+    }
+
+    lowered
+}
+
+/// Reports every reference that still needs an absolute address.
+pub fn verify(instructions: &[Line]) {
+    let mut reported = HashSet::new();
+
+    for line in instructions.iter() {
+        let name = match &line.0 {
+            Control::ReqLabel =>        line.3.last(),
+            Control::ReqDataPointer =>  line.3.get(1),
+            Control::ReqOffset(_) =>    line.3.last(),
+            Control::ReqTable(_) =>     line.3.first(),
+
+            _ => continue,
+        };
+
+        if reported.insert((line.4.clone(), line.5)) {
+            let what = if matches!(line.0, Control::ReqTable(_)) { String::from("`#table` entries") } else { format!("`{}`", name.map(|n| n.as_str()).unwrap_or("")) };
+            error(line.clone(), &format!("Absolute address of {} in position-independent code.", what));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collect_errors, lex_source, link, sim};
+
+    #[test]
+    fn same_bytes_anywhere() {
+        let source = indoc::indoc! {"
+            la r1 value
+            call double
+            mov r2 3
+            #while r2 != r0
+                dec r2
+                call double
+            #endwhile
+            hlt
+            double:
+            add r1 r1 r1
+            ret
+            value:
+        "};
+        let build = |base: usize| {
+            let instructions = lower(lex_source(source, "test.asm"));
+            verify(&instructions);
+            link(instructions, ".", base).1
+        };
+        let (bytes, errors) = collect_errors(|| (build(0x1000), build(0x7_0000)));
+        assert_eq!((errors.len(), &bytes.0), (0, &bytes.1));
+
+        for base in [0x1000, 0x7_0000] {
+            let mut machine = sim::Machine::new(&bytes.0, base, base + 0x8000);
+            assert_eq!(machine.run(1000), sim::Stop::Halted);
+            assert_eq!(machine.regs[1], 16 * (base + bytes.0.len()) as u64);
+        }
+    }
+
+    #[test]
+    fn carry_jumps() {
+        let source = indoc::indoc! {"
+            mov r1 &ffff_ffff_ffff_ffff
+            mov r2 2
+            add r3 r1 r2
+            jpc taken
+            mov r4 1
+            taken:
+            jnc skipped
+            mov r5 1
+            skipped:
+            add r6 r2 r2
+            jnc cleared
+            mov r7 1
+            cleared:
+            add r8 r1 r1
+            jpc done
+            mov r9 1
+            done:
+            hlt
+        "};
+        let run = |instructions: Vec<Line>| {
+            let bytes = link(instructions, ".", 0x1000).1;
+            let mut machine = sim::Machine::new(&bytes, 0x1000, 0x8000);
+            assert_eq!(machine.run(1000), sim::Stop::Halted);
+            (machine.regs[..0xfe].to_vec(), machine.carry, machine.sp)
+        };
+
+        let absolute = run(lex_source(source, "test.asm"));
+        assert_eq!((&absolute.0[4..10], absolute.1), (&[0, 1, 4, 0, 0xffff_ffff_ffff_fffe, 0][..], true));
+        assert_eq!(run(lower(lex_source(source, "test.asm"))), absolute);
+    }
+
+    #[test]
+    fn absolute_references_are_errors() {
+        let (_, errors) = collect_errors(|| verify(&lower(lex_source("a:\njpe rff r1 a\nla rfe a\n#table t a\n", "test.asm"))));
+        assert_eq!(errors.iter().map(|e| e.1.as_str()).collect::<Vec<_>>(), vec![
+            "`rfe` and `rff` are scratch registers with --pic.",
+            "`rfe` is a scratch register with --pic.",
+            "Absolute address of `a` in position-independent code.",
+            "Absolute address of `a` in position-independent code.",
+            "Absolute address of `#table` entries in position-independent code.",
+        ]);
+    }
+}
@@ -0,0 +1,115 @@
+//! Relocation table, every place the linker wrote an absolute address. A loader that puts the
+//! program somewhere else than the base it was linked at adds the difference to each of them.
+//!
+//!     0x00  magic "VMRL"
+//!     0x04  version        u16
+//!     0x06  reserved       u16, 0
+//!     0x08  linked base    u32
+//!     0x0C  entry count    u32
+//!     0x10  entries, per relocation:
+//!           offset from the base u32, width in bytes u32
+//!
+//! Patched values are big endian like everything else. PC-relative references of `--pic` need
+//! no relocation.
+
+use crate::{Control, Line};
+
+pub const MAGIC: &[u8; 4] = b"VMRL";
+pub const VERSION: u16 = 1;
+
+pub const HEADER_LEN: usize = 0x10;
+pub const ENTRY_LEN: usize = 0x08;
+
+/// A patched address as (offset from the base, width in bytes).
+pub type Relocation = (usize, usize);
+
+/// The relocations of linked instructions, in address order.
+pub fn collect(instructions: &[Line]) -> Vec<Relocation> {
+    let mut relocations = Vec::new();
+
+    let mut offset = 0;
+    for inst in instructions.iter() {
+        let l = inst.1.len();
+
+        match inst.0 {
+            Control::ReqLabel | Control::ReqOffset(_) =>  relocations.push((offset + l - 4, 4)),
+            Control::ReqDataPointer =>                  relocations.push((offset + 1, 4)),
+            Control::ReqTable(width) =>                 relocations.extend((0..l / width).map(|n| (offset + n * width, width))),
+
+            _ => {}
+        }
+        offset += l;
+    }
+
+    relocations
+}
+
+/// Encodes the table for a program linked at `align`.
+pub fn table(relocations: &[Relocation], align: usize) -> Vec<u8> {
+    let mut buf = MAGIC.to_vec();
+    buf.extend_from_slice(&VERSION.to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    buf.extend_from_slice(&(align as u32).to_be_bytes());
+    buf.extend_from_slice(&(relocations.len() as u32).to_be_bytes());
+
+    for (offset, width) in relocations.iter() {
+        buf.extend_from_slice(&(*offset as u32).to_be_bytes());
+        buf.extend_from_slice(&(*width as u32).to_be_bytes());
+    }
+
+    buf
+}
+
+/// Reads a table back as (linked base, relocations).
+pub fn parse(bytes: &[u8]) -> Result<(usize, Vec<Relocation>), &'static str> {
+    let be = |b: &[u8]| u32::from_be_bytes(b.try_into().unwrap()) as usize;
+
+    if bytes.len() < HEADER_LEN || !bytes.starts_with(MAGIC) { return Err("Not a relocation table.") }
+    let count = be(&bytes[12..16]);
+    let entries = bytes.get(HEADER_LEN..HEADER_LEN + count * ENTRY_LEN).ok_or("Truncated relocation table.")?;
+
+    Ok((be(&bytes[8..12]), entries.chunks(ENTRY_LEN).map(|e| (be(&e[..4]), be(&e[4..]))).collect()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex_source, link, sim};
+
+    /// Moves `bytes`, linked at the base stored in `table`, to `base`, like a loader would.
+    fn relocate(bytes: &mut [u8], table: &[u8], base: usize) -> Result<(), &'static str> {
+        let (linked, relocations) = parse(table)?;
+
+        for (offset, width) in relocations {
+            let field = bytes.get_mut(offset..offset + width).ok_or("Relocation past the end of the program.")?;
+            let value = field.iter().fold(0u64, |v, b| v << 8 | *b as u64).wrapping_add(base as u64).wrapping_sub(linked as u64);
+            field.copy_from_slice(&value.to_be_bytes()[8 - width..]);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn relocated_program_runs() {
+        let source = indoc::indoc! {"
+            la r1 value
+            call double
+            hlt
+            double:
+            add r1 r1 r1
+            ret
+            #table value 2 double
+        "};
+        let (instructions, mut bytes) = link(lex_source(source, "test.asm"), ".", 0x1000);
+        let relocations = collect(&instructions);
+        assert_eq!(relocations, vec![(6, 4), (16, 4), (23, 4), (36, 2)]);
+
+        relocate(&mut bytes, &table(&relocations, 0x1000), 0x4000).unwrap();
+        assert_eq!(bytes, link(lex_source(source, "test.asm"), ".", 0x4000).1);
+
+        let mut machine = sim::Machine::new(&bytes, 0x4000, 0x8000);
+        assert_eq!(machine.run(100), sim::Stop::Halted);
+        assert_eq!(machine.regs[1], 2 * (0x4000 + 36));
+    }
+}
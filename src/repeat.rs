@@ -0,0 +1,164 @@
+//! Compile-time repetition, expanded before the lines are encoded.
+//!
+//!     #rep 4                  #for i in 0..8
+//!     add r1 r1 r2            db i*i i*2+1
+//!     #endrep                 #endfor
+//!
+//! Counts and bounds are constant expressions, `a..b` excludes `b`. The `#for` variable is
+//! replaced in the body wherever it stands as a whole word, so it can be used in operand
+//! expressions. Every copy keeps the source line of its body line.
+
+use std::collections::HashMap;
+
+use crate::{error, expr::constant, replace_words, tokenize, Control, Line};
+
+/// Copies a single block may expand to, guards against typos like `#rep 100000000`.
+pub const MAX_COPIES: i64 = 0x10000;
+
+/// Lines a whole file may expand to, nested blocks multiply their copies.
+pub const MAX_LINES: usize = 0x10_0000;
+
+/// Source text of one line with its line number.
+pub type SourceLine = (usize, String);
+
+fn line(path: &str, source: &SourceLine) -> Line {
+    let parts = tokenize(&source.1);
+    (Control::None, Vec::new(), parts.first().cloned().unwrap_or_default(), parts.iter().skip(1).cloned().collect(), path.to_owned(), source.0)
+}
+
+fn keyword(text: &str) -> String {
+    text.split_whitespace().next().unwrap_or("").to_lowercase()
+}
+
+/// The values a `#rep` or `#for` line repeats its body for, with the `#for` variable.
+fn copies(text: &str) -> Result<(Option<String>, Vec<i64>), String> {
+    let parts = text.split_whitespace().skip(1).collect::<Vec<&str>>();
+
+    let (name, range) = match keyword(text).as_str() {
+        "#rep" => (None, 0..constant(&parts.join(" "))?),
+        _ => {
+            let (name, range) = match parts.as_slice() {
+                [name, "in", range @ ..] if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') => (name, range.join(" ")),
+
+                _ => return Err(String::from("Expected `#for <name> in <start>..<end>`.")),
+            };
+            let (start, end) = range.split_once("..").ok_or("Expected `#for <name> in <start>..<end>`.")?;
+            (Some(name.to_string()), constant(start)?..constant(end)?)
+        }
+    };
+
+    if range.end.saturating_sub(range.start) > MAX_COPIES { return Err(format!("More than {} copies.", MAX_COPIES)) }
+    Ok((name, range.collect()))
+}
+
+/// Expands every `#rep`/`#for` block of `lines`, inner blocks after the outer variable is
+/// replaced so their bounds can use it.
+pub fn expand(lines: &[SourceLine], path: &str) -> Vec<SourceLine> {
+    let mut expanded = Vec::new();
+    expand_into(lines, path, &mut expanded);
+    expanded
+}
+
+/// Appends the expansion of `lines` to `expanded`, false once it grew past `MAX_LINES`.
+fn expand_into(lines: &[SourceLine], path: &str, expanded: &mut Vec<SourceLine>) -> bool {
+    let mut i = 0;
+    while i < lines.len() {
+        let opening = &lines[i];
+        let close = match keyword(&opening.1).as_str() {
+            "#rep" => "#endrep",
+            "#for" => "#endfor",
+            "#endrep" | "#endfor" => {
+                error(line(path, opening), &format!("`{}` without `{}`.", keyword(&opening.1), if keyword(&opening.1) == "#endrep" { "#rep" } else { "#for" }));
+                i += 1;
+                continue
+            }
+
+            _ => {
+                expanded.push(opening.clone());
+                i += 1;
+                continue
+            }
+        };
+
+        // Finds the closing line, nested blocks of either kind count.
+        let mut depth = 0;
+        let end = lines[i + 1..].iter().position(|(_, text)| {
+            match keyword(text).as_str() {
+                "#rep" | "#for" =>          { depth += 1; false }
+                "#endrep" | "#endfor" if depth > 0 => { depth -= 1; false }
+                "#endrep" | "#endfor" =>    true,
+
+                _ => false,
+            }
+        }).map(|p| i + 1 + p);
+
+        let Some(end) = end.filter(|e| keyword(&lines[*e].1) == close) else {
+            error(line(path, opening), &format!("`{}` without `{}`.", keyword(&opening.1), close));
+            i += 1;
+            continue
+        };
+        let body = &lines[i + 1..end];
+
+        if let Some(label) = body.iter().find(|(_, text)| keyword(text).ends_with(":")) {
+            error(line(path, label), "Labels can't be repeated, they'd be defined once per copy.");
+        }
+
+        match copies(&opening.1) {
+            Ok((name, values)) => {
+                for value in values {
+                    if expanded.len() > MAX_LINES {
+                        error(line(path, opening), &format!("Expands to more than {} lines.", MAX_LINES));
+                        return false;
+                    }
+
+                    let words = name.iter().map(|name| (name.clone(), value.to_string())).collect::<HashMap<String, String>>();
+                    let copy = body.iter().map(|(n, text)| (*n, replace_words(text, "_", &words))).collect::<Vec<SourceLine>>();
+                    if !expand_into(&copy, path, expanded) { return false }
+                }
+            }
+            Err(e) => error(line(path, opening), &e),
+        }
+
+        i = end + 1;
+    }
+
+    true
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect_errors;
+
+    fn source(text: &str) -> Vec<SourceLine> {
+        text.lines().enumerate().map(|(i, l)| (i + 1, l.to_owned())).collect()
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let lines = source("#for i in 1..3\n#rep i\ndb i*4 ri\n#endrep\n#endfor\nhlt");
+        assert_eq!(expand(&lines, "test.asm"), vec![
+            (3, String::from("db 1*4 ri")),
+            (3, String::from("db 2*4 ri")),
+            (3, String::from("db 2*4 ri")),
+            (6, String::from("hlt")),
+        ]);
+    }
+
+    #[test]
+    fn block_errors() {
+        let (_, errors) = collect_errors(|| expand(&source("#rep 2\nloop:\n#endrep\n#for x 0..2\n#endfor\n#rep 1\n#endfor\n#endrep"), "test.asm"));
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![
+            (2, "Labels can't be repeated, they'd be defined once per copy."),
+            (4, "Expected `#for <name> in <start>..<end>`."),
+            (6, "`#rep` without `#endrep`."),
+            (7, "`#endfor` without `#for`."),
+            (8, "`#endrep` without `#rep`."),
+        ]);
+
+        let (expanded, errors) = collect_errors(|| expand(&source("#rep 0x1000\n#rep 0x1000\nnop\n#endrep\n#endrep\nhlt"), "test.asm"));
+        assert!(expanded.len() <= MAX_LINES + 0x1000);
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![(2, "Expands to more than 1048576 lines.")]);
+    }
+}
@@ -0,0 +1,409 @@
+//! Headless interpreter for assembled vm64 programs.
+//!
+//! Memory operand conventions: `mov` addresses are relative to the load base, `mva` addresses
+//! are absolute and `mvd` addresses go to the drive. Loads zero extend, stores keep the low
+//! `len` bytes, everything is big endian. `gpc` yields the address of the next instruction and
+//! the stack grows down in 8 byte slots. Float registers hold `f64`, literals moved into them
+//! are raw bit patterns and moves between register kinds convert the value.
+
+use std::collections::HashMap;
+
+use crate::fb::Framebuffer;
+
+const PAGE: usize = 0x1000;
+
+/// Longest `memcpy` with the length in a register, the same as the 3 byte immediate allows.
+pub const MAX_COPY: usize = 0xFF_FFFF;
+
+#[derive(Debug, Default, Clone)]
+pub struct Memory {
+    pages: HashMap<usize, Vec<u8>>,
+}
+
+impl Memory {
+    pub fn read(&self, address: usize, len: usize) -> Vec<u8> {
+        (0..len).map(|i| address.wrapping_add(i)).map(|a| self.pages.get(&(a / PAGE)).map(|p| p[a % PAGE]).unwrap_or(0)).collect()
+    }
+
+    pub fn write(&mut self, address: usize, bytes: &[u8]) {
+        for (i, n) in bytes.iter().enumerate() {
+            let a = address.wrapping_add(i);
+            self.pages.entry(a / PAGE).or_insert_with(|| vec![0; PAGE])[a % PAGE] = *n;
+        }
+    }
+
+    pub fn read_be(&self, address: usize, len: usize) -> u64 {
+        self.read(address, len).iter().fold(0, |v, n| (v << 8) | *n as u64)
+    }
+
+    pub fn write_be(&mut self, address: usize, value: u64, len: usize) {
+        self.write(address, &value.to_be_bytes()[8 - len.min(8)..]);
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stop {
+    Halted,
+    Limit,
+    Fault(String),
+}
+
+pub struct Machine {
+    pub regs: [u64; 256],
+    pub fregs: [f64; 256],
+    pub carry: bool,
+    pub pc: usize,
+    pub sp: usize,
+    pub base: usize,
+    pub memory: Memory,
+    pub drive: Memory,
+    pub cycles: usize,
+    pub ports: Vec<(u16, u64)>,
+    pub framebuffer: Option<Framebuffer>,
+}
+
+impl Machine {
+    /// Loads `bytes` at `base` and starts executing from there.
+    pub fn new(bytes: &[u8], base: usize, stack: usize) -> Machine {
+        let mut memory = Memory::default();
+        memory.write(base, bytes);
+
+        Machine {
+            regs: [0; 256],
+            fregs: [0.0; 256],
+            carry: false,
+            pc: base,
+            sp: stack,
+            base,
+            memory,
+            drive: Memory::default(),
+            cycles: 0,
+            ports: Vec::new(),
+            framebuffer: None,
+        }
+    }
+
+    pub fn run(&mut self, limit: usize) -> Stop {
+        while self.cycles < limit {
+            match self.step() {
+                Ok(true) => return Stop::Halted,
+                Ok(false) => {}
+                Err(e) => return Stop::Fault(e),
+            }
+        }
+        Stop::Limit
+    }
+
+    fn fetch(&self, offset: usize, len: usize) -> u64 {
+        self.memory.read_be(self.pc.wrapping_add(offset), len)
+    }
+
+    fn byte(&self, offset: usize) -> usize {
+        self.fetch(offset, 1) as usize
+    }
+
+    fn push(&mut self, value: u64) {
+        self.sp = self.sp.wrapping_sub(8);
+        self.memory.write_be(self.sp, value, 8);
+    }
+
+    fn pop(&mut self) -> u64 {
+        let value = self.memory.read_be(self.sp, 8);
+        self.sp = self.sp.wrapping_add(8);
+        value
+    }
+
+    /// Executes one instruction, returns `Ok(true)` on `hlt`.
+    pub fn step(&mut self) -> Result<bool, String> {
+        let op = self.fetch(0, 1) as u8;
+        let pc = self.pc;
+        let (b1, b2, b3) = (self.byte(1), self.byte(2), self.byte(3));
+
+        let len = length(op).ok_or_else(|| format!("Invalid opcode 0x{:02x} at 0x{:08x}.", op, pc))?;
+        let next = pc.wrapping_add(len);
+        let mut jump = None;
+
+        self.cycles += 1;
+
+        match op {
+            0x00 => {}
+
+            0x01 => { self.regs[b1] = self.regs[b2] }
+            0x02 => { self.fregs[b1] = self.fregs[b2] }
+            0x03 => { self.fregs[b1] = self.regs[b2] as f64 }
+            0x04 => { self.regs[b1] = self.fregs[b2] as u64 }
+            0x05 => { self.regs[b1] = self.fetch(2, 8) }
+            0x06 => { self.fregs[b1] = f64::from_bits(self.fetch(2, 8)) }
+
+            // Loads and stores, `mov` relative to the base, `mva` absolute and `mvd` on the drive.
+            0x07..=0x2A => {
+                let (space, kind) = match op {
+                    0x07..=0x0E => (0, op - 0x07),
+                    0x0F..=0x16 => (1, op - 0x0F),
+                    0x17..=0x1A => (0, op - 0x17 + 8),
+                    0x1B..=0x1E => (1, op - 0x1B + 8),
+                    0x1F..=0x26 => (2, op - 0x1F),
+
+                    _ =>           (2, op - 0x27 + 8),
+                };
+                let relative = |a: usize| if space == 0 { self.base.wrapping_add(a) } else { a };
+
+                // (store, float, destination register, address, len, source register)
+                let (store, float, dest, address, len, src) = match kind {
+                    0 | 1 => (false, kind == 1, b1, relative(self.fetch(3, 4) as usize), b2, 0),
+                    2 | 3 => (true, kind == 3, 0, relative(self.fetch(1, 4) as usize), self.byte(5), self.byte(6)),
+                    4 | 5 => (true, kind == 5, 0, relative(self.regs[b1] as usize), b2, b3),
+                    6 | 7 => (false, kind == 7, b1, relative(self.regs[b3] as usize), b2, 0),
+                    8 | 9 => (true, kind == 9, 0, relative((self.regs[b1] as usize).wrapping_add(self.fetch(4, 4) as usize)), b2, b3),
+
+                    _ =>     (false, kind == 11, b1, relative((self.regs[b3] as usize).wrapping_add(self.fetch(4, 4) as usize)), b2, 0),
+                };
+
+                let memory = if space == 2 { &mut self.drive } else { &mut self.memory };
+                if store {
+                    let value = if float { self.fregs[src].to_bits() } else { self.regs[src] };
+                    memory.write_be(address, value, len);
+                }
+                else {
+                    let value = memory.read_be(address, len);
+                    if float { self.fregs[dest] = f64::from_bits(value) } else { self.regs[dest] = value }
+                }
+            }
+
+            0x30..=0x39 => {
+                if op % 2 == 1 {
+                    let (a, b) = (self.fregs[b2], self.fregs[b3]);
+                    self.fregs[b1] = match op {
+                        0x31 => a + b,
+                        0x33 => a - b,
+                        0x35 => a * b,
+                        0x37 => a / b,
+
+                        _ =>    a % b,
+                    };
+                }
+                else {
+                    let (a, b) = (self.regs[b2], self.regs[b3]);
+                    self.regs[b1] = match op {
+                        0x30 => { let (v, c) = a.overflowing_add(b); self.carry = c; v }
+                        0x32 => { let (v, c) = a.overflowing_sub(b); self.carry = c; v }
+                        0x34 => { let (v, c) = a.overflowing_mul(b); self.carry = c; v }
+                        0x36 => { a.checked_div(b).ok_or_else(|| format!("Division by zero at 0x{:08x}.", pc))? }
+
+                        _ =>    { a.checked_rem(b).ok_or_else(|| format!("Division by zero at 0x{:08x}.", pc))? }
+                    };
+                }
+            }
+            0x3A => { self.regs[b1] = self.regs[b2].checked_shl(self.regs[b3] as u32).unwrap_or(0) }
+            0x3B => { self.regs[b1] = self.regs[b2].checked_shr(self.regs[b3] as u32).unwrap_or(0) }
+            0x3C => { self.regs[b1] = self.regs[b2] & self.regs[b3] }
+            0x3D => { self.regs[b1] = self.regs[b2] | self.regs[b3] }
+            0x3E => { self.regs[b1] = self.regs[b2] ^ self.regs[b3] }
+            0x3F => { self.regs[b1] = !self.regs[b2] }
+            0x40 => { let (v, c) = self.regs[b1].overflowing_add(1); self.regs[b1] = v; self.carry = c }
+            0x41 => { let (v, c) = self.regs[b1].overflowing_sub(1); self.regs[b1] = v; self.carry = c }
+            0x42 => { self.push(self.regs[b1]) }
+            0x43 => { self.push(self.fregs[b1].to_bits()) }
+            0x44 => { self.regs[b1] = self.pop() }
+            0x45 => { self.fregs[b1] = f64::from_bits(self.pop()) }
+            0x46 => { let (v, c) = self.regs[b1].overflowing_add(self.carry as u64); self.regs[b1] = v; self.carry = c }
+            0x47 => { let (v, c) = self.regs[b1].overflowing_sub(self.carry as u64); self.regs[b1] = v; self.carry = c }
+            0x48 => { self.carry = true }
+            0x49 => { self.carry = false }
+
+            0x50 => { jump = Some(self.fetch(1, 4) as usize) }
+            0x51 => { jump = Some(self.regs[b1] as usize) }
+            0x52..=0x5D => {
+                let float = matches!(op, 0x54 | 0x55 | 0x58 | 0x59 | 0x5C | 0x5D);
+                let taken = match (op - 0x52) / 4 {
+                    0 => if float { self.fregs[b1] < self.fregs[b2] } else { self.regs[b1] < self.regs[b2] },
+                    1 => if float { self.fregs[b1] == self.fregs[b2] } else { self.regs[b1] == self.regs[b2] },
+
+                    _ => if float { self.fregs[b1] != self.fregs[b2] } else { self.regs[b1] != self.regs[b2] },
+                };
+                if taken {
+                    jump = Some(if op.is_multiple_of(2) { self.fetch(3, 4) as usize } else { self.regs[b3] as usize });
+                }
+            }
+            0x5E => { if self.carry { jump = Some(self.fetch(1, 4) as usize) } }
+            0x5F => { if self.carry { jump = Some(self.regs[b1] as usize) } }
+            0x60 => { if !self.carry { jump = Some(self.fetch(1, 4) as usize) } }
+            0x61 => { if !self.carry { jump = Some(self.regs[b1] as usize) } }
+
+            0x70 => { self.pc = next; return Ok(true) }
+            0x71 | 0x72 => {}
+            0x73 => { self.regs[b1] = self.carry as u64 }
+            0x74 => { self.regs[b1] = next as u64 }
+
+            0x80 | 0x81 => {}
+            0x82 => {
+                let (src, dst, len) = (self.fetch(1, 4) as usize, self.fetch(5, 4) as usize, self.fetch(9, 3) as usize);
+                let bytes = self.memory.read(src, len);
+                self.memory.write(dst, &bytes);
+            }
+            0x83 | 0x84 => {
+                let len = if op == 0x83 { self.fetch(3, 3) } else { self.regs[b3] } as usize;
+                if len > MAX_COPY { return Err(format!("memcpy of 0x{:x} bytes at 0x{:08x}, at most 0x{:x} are copied at once.", len, pc, MAX_COPY)) }
+                let bytes = self.memory.read(self.regs[b1] as usize, len);
+                self.memory.write(self.regs[b2] as usize, &bytes);
+            }
+
+            0x90 => { self.ports.push((self.fetch(2, 2) as u16, self.regs[b1])) }
+            0x91 => { self.ports.push((self.regs[b2] as u16, self.regs[b1])) }
+            0x92 | 0x93 => { self.regs[b1] = 0 }
+
+            // Drawing is handled by the framebuffer device, without one it does nothing.
+            0xA0 | 0xA1 => {
+                if let Some(fb) = &self.framebuffer {
+                    let (src, dst, h, w, x, y) = if op == 0xA0 {
+                        (self.fetch(1, 4) as usize, self.regs[self.byte(5)] as usize, self.fetch(6, 2), self.fetch(8, 2), self.fetch(10, 2), self.fetch(12, 2))
+                    }
+                    else {
+                        let r = |i: usize| self.regs[self.byte(i)];
+                        (r(1) as usize, r(2) as usize, r(3), r(4), r(5), r(6))
+                    };
+                    fb.blit(&mut self.memory, src, dst, x as usize, y as usize, w as usize, h as usize);
+                }
+            }
+
+            _ => { return Err(format!("Invalid opcode 0x{:02x} at 0x{:08x}.", op, pc)) }
+        }
+
+        self.pc = jump.unwrap_or(next);
+        Ok(false)
+    }
+
+    /// Formats the non zero registers, the flags and the requested memory ranges.
+    pub fn dump(&self, ranges: &[(usize, usize)]) -> String {
+        let mut buf = format!("pc 0x{:08x}  sp 0x{:08x}  carry {}  cycles {}\n", self.pc, self.sp, self.carry as u8, self.cycles);
+
+        for (i, r) in self.regs.iter().enumerate().filter(|r| *r.1 != 0) {
+            buf.push_str(&format!("r{:<2x} 0x{:016x}  {}\n", i, r, r));
+        }
+        for (i, f) in self.fregs.iter().enumerate().filter(|f| *f.1 != 0.0) {
+            buf.push_str(&format!("f{:<2x} {}\n", i, f));
+        }
+        for (port, value) in self.ports.iter() {
+            buf.push_str(&format!("out 0x{:04x} 0x{:x}\n", port, value));
+        }
+
+        for (address, len) in ranges.iter() {
+            buf.push_str(&format!("memory 0x{:08x}..0x{:08x}\n", address, address + len));
+            for (i, chunk) in self.memory.read(*address, *len).chunks(16).enumerate() {
+                buf.push_str(&format!("0x{:08x}  {}\n", address + i * 16, chunk.iter().map(|n| format!("{:02x}", n)).collect::<Vec<String>>().join(" ")));
+            }
+        }
+
+        buf
+    }
+}
+
+/// Encoded length of every opcode `resolve_inst` can produce.
+pub fn length(op: u8) -> Option<usize> {
+    match op {
+        0x00 | 0x48 | 0x49 | 0x70 | 0x71 | 0x80 | 0x81 => Some(1),
+        0x40..=0x47 | 0x51 | 0x5F | 0x61 | 0x72..=0x74 => Some(2),
+        0x01..=0x04 | 0x3F | 0x91 | 0x93 => Some(3),
+        0x0B..=0x0E | 0x13..=0x16 | 0x23..=0x26 | 0x30..=0x3E | 0x53 | 0x55 | 0x57 | 0x59 | 0x5B | 0x5D | 0x84 | 0x90 | 0x92 => Some(4),
+        0x50 | 0x5E | 0x60 => Some(5),
+        0x83 => Some(6),
+        0x07..=0x0A | 0x0F..=0x12 | 0x1F..=0x22 | 0x52 | 0x54 | 0x56 | 0x58 | 0x5A | 0x5C | 0xA1 => Some(7),
+        0x17..=0x1E | 0x27..=0x2A => Some(8),
+        0x05 | 0x06 => Some(10),
+        0x82 => Some(12),
+        0xA0 => Some(14),
+
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{lex_source, link};
+
+    fn run(source: &str) -> (Machine, Stop) {
+        let (_, bytes) = link(lex_source(source, "test.asm"), ".", 0x1000);
+        let mut machine = Machine::new(&bytes, 0x1000, 0x1000);
+        let stop = machine.run(10_000);
+        (machine, stop)
+    }
+
+    #[test]
+    fn loop_and_arithmetic() {
+        let (m, stop) = run(indoc::indoc! {"
+            mov r0 0
+            mov r1 1
+            mov r2 10
+            loop:
+            add r0 r0 r1
+            jlg r0 r2 loop
+            mul r3 r0 r2
+            hlt
+        "});
+
+        assert_eq!(stop, Stop::Halted);
+        assert_eq!((m.regs[0], m.regs[3]), (10, 100));
+    }
+
+    #[test]
+    fn stack_memory_and_carry() {
+        let (m, stop) = run(indoc::indoc! {"
+            mov r0 &ffff_ffff_ffff_ffff
+            mov r1 1
+            add r2 r0 r1
+            gst r3
+            psh r1
+            pop r4
+            mva &2000 r1 2
+            memcpy &3000 &2000 2
+            mva r5 2 &3000
+            mov f0 &3ff8_0000_0000_0000
+            add f1 f0 f0
+            hlt
+        "});
+
+        assert_eq!(stop, Stop::Halted);
+        assert_eq!((m.regs[2], m.regs[3], m.regs[4], m.regs[5]), (0, 1, 1, 1));
+        assert_eq!(m.memory.read(0x3000, 2), vec![0x00, 0x01]);
+        assert_eq!(m.fregs[1], 3.0);
+        assert_eq!(m.sp, 0x1000);
+    }
+
+    #[test]
+    fn memory_wraps_around() {
+        let (m, stop) = run("mov r0 &ffff_ffff_ffff_ffff\nmov r1 &0102\nmva r0 r1 2\nmva r2 2 r0\nhlt\n");
+
+        assert_eq!(stop, Stop::Halted);
+        assert_eq!(m.regs[2], 0x0102);
+        assert_eq!(m.memory.read(0, 1), vec![0x02]);
+    }
+
+    #[test]
+    fn grapcpy_to_framebuffer() {
+        let source = "#bytes dot dot.bin\nmov r0 &8000\ngrapcpy r0 dot 1 1 1 1\nhlt\n";
+        let dir = std::env::temp_dir().join(format!("vm64-sim-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("dot.bin"), [0xFF, 0x80, 0x00]).unwrap();
+
+        let (_, bytes) = link(lex_source(source, "test.asm"), dir.to_str().unwrap(), 0x1000);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut machine = Machine::new(&bytes, 0x1000, 0x1000);
+        machine.framebuffer = Some(Framebuffer { base: 0x8000, width: 2, height: 2, format: crate::fb::PixelFormat::Rgb8 });
+
+        assert_eq!(machine.run(100), Stop::Halted);
+        assert_eq!(machine.framebuffer.as_ref().unwrap().snapshot(&machine.memory).get_pixel(1, 1).0, [0xFF, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn limit_and_fault() {
+        assert_eq!(run("loop:\njmp loop\n").1, Stop::Limit);
+        assert_eq!(run("mov r0 1\ndiv r0 r0 r1\n").1, Stop::Fault(String::from("Division by zero at 0x0000100a.")));
+        assert_eq!(run("mov r2 &100_0000\nmemcpy r0 r1 r2\n").1, Stop::Fault(String::from("memcpy of 0x1000000 bytes at 0x0000100a, at most 0xffffff are copied at once.")));
+
+        let (m, stop) = run("mov r0 &ffff_ffff_ffff_ffff\nmov r1 &70\nmva r2 r1 1\njmp r0\n");
+        assert_eq!((stop, m.pc), (Stop::Halted, 1));
+    }
+}
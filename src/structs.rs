@@ -0,0 +1,184 @@
+//! Struct layouts and instances, resolved on the source text of every file before lexing.
+//!
+//!     #struct Sprite { x: 2, y: 2, w: 2, h: 2, data: 4 }
+//!     #instance Sprite hero { x: 10, y: 20, w: 16, h: 16 }
+//!
+//!     mva r1 2 Sprite.y r0        ; load hero.y with r0 pointing at hero
+//!
+//! Fields are laid out in order without padding. `Name.field` is the offset of a field and
+//! `Name.size` the size of the whole struct, they can be used in any file. An instance is a
+//! label followed by the fields as big endian bytes, fields it leaves out are 0.
+
+use std::collections::HashMap;
+
+use crate::{error, expr::eval, replace_words, tokenize, Control, Line};
+
+/// A struct with its fields as (name, size) in layout order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    pub name: String,
+    pub fields: Vec<(String, usize)>,
+}
+
+impl Layout {
+    pub fn size(&self) -> usize {
+        self.fields.iter().map(|f| f.1).sum()
+    }
+
+    pub fn offset(&self, field: &str) -> Option<usize> {
+        let i = self.fields.iter().position(|f| f.0 == field)?;
+        Some(self.fields[..i].iter().map(|f| f.1).sum())
+    }
+}
+
+fn line(path: &str, n: usize, text: &str) -> Line {
+    let parts = tokenize(text);
+    (Control::None, Vec::new(), parts.first().cloned().unwrap_or_default(), parts.iter().skip(1).cloned().collect(), path.to_owned(), n + 1)
+}
+
+fn keyword(text: &str) -> String {
+    text.split_whitespace().next().unwrap_or("").to_lowercase()
+}
+
+fn is_name(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `field: value` pairs between the braces of a directive.
+type Fields<'a> = Vec<(&'a str, &'a str)>;
+
+/// Splits `<head> { a: x, b: y }` into the head words and the fields.
+fn braces(text: &str) -> Result<(Vec<&str>, Fields<'_>), String> {
+    let code = text.split(";").next().unwrap();
+    let (head, rest) = code.split_once("{").ok_or("Expected `{`.")?;
+    let (body, tail) = rest.split_once("}").ok_or("Expected `}`.")?;
+    if !tail.trim().is_empty() { return Err(format!("Unexpected `{}` after `}}`.", tail.trim())) }
+
+    let fields = body.split(",").map(|f| f.trim()).filter(|f| !f.is_empty()).map(|f| {
+        let (name, value) = f.split_once(":").ok_or_else(|| format!("Expected `<field>: <value>`, got `{}`.", f))?;
+        Ok((name.trim(), value.trim()))
+    }).collect::<Result<Fields, String>>()?;
+
+    Ok((head.split_whitespace().skip(1).collect(), fields))
+}
+
+fn parse_layout(text: &str, defines: &HashMap<String, String>) -> Result<Layout, String> {
+    let (head, fields) = braces(text)?;
+    let [name] = head.as_slice() else { return Err(String::from("Expected `#struct <Name> { <field>: <size>, ... }`.")) };
+    if !is_name(name) { return Err(format!("`{}` isn't a valid struct name.", name)) }
+
+    let mut layout = Layout { name: name.to_string(), fields: Vec::new() };
+    for (field, size) in fields {
+        if !is_name(field) || field == "size" { return Err(format!("`{}` isn't a valid field name.", field)) }
+        if layout.offset(field).is_some() { return Err(format!("Field `{}` is defined twice.", field)) }
+
+        match eval(size, defines)? {
+            size @ 1..=8 => layout.fields.push((field.to_owned(), size as usize)),
+            size => return Err(format!("Field `{}` is {} bytes, fields are 1 to 8 bytes.", field, size)),
+        }
+    }
+
+    Ok(layout)
+}
+
+/// Collects the `#struct` layouts of every file.
+pub fn collect(sources: &[(String, String)], defines: &HashMap<String, String>) -> Vec<Layout> {
+    let mut layouts: Vec<Layout> = Vec::new();
+
+    for (path, source) in sources.iter() {
+        for (n, text) in source.split("\n").enumerate().filter(|(_, t)| keyword(t) == "#struct") {
+            match parse_layout(text, defines) {
+                Ok(layout) if layouts.iter().any(|l| l.name == layout.name) => error(line(path, n, text), &format!("Struct `{}` is defined twice.", layout.name)),
+                Ok(layout) => layouts.push(layout),
+                Err(e) => error(line(path, n, text), &e),
+            }
+        }
+    }
+
+    layouts
+}
+
+/// `Name.field` and `Name.size` of every layout.
+pub fn constants(layouts: &[Layout]) -> HashMap<String, String> {
+    let mut constants = HashMap::new();
+
+    for layout in layouts.iter() {
+        constants.insert(format!("{}.size", layout.name), layout.size().to_string());
+        for (field, _) in layout.fields.iter() {
+            constants.insert(format!("{}.{}", layout.name, field), layout.offset(field).unwrap().to_string());
+        }
+    }
+
+    constants
+}
+
+/// Bytes of an `#instance` line, with the label it defines.
+fn instance(text: &str, layouts: &[Layout], defines: &HashMap<String, String>) -> Result<(String, Vec<u8>), String> {
+    let (head, values) = braces(text)?;
+    let [kind, name] = head.as_slice() else { return Err(String::from("Expected `#instance <Struct> <name> { <field>: <value>, ... }`.")) };
+    let layout = layouts.iter().find(|l| l.name == *kind).ok_or_else(|| format!("Unknown struct `{}`.", kind))?;
+
+    let mut bytes = vec![0; layout.size()];
+    for (field, value) in values {
+        let offset = layout.offset(field).ok_or_else(|| format!("`{}` has no field `{}`.", kind, field))?;
+        let size = layout.fields.iter().find(|f| f.0 == field).unwrap().1;
+
+        let value = eval(value, defines)?;
+        if size < 8 && (value < -(1 << (size * 8 - 1)) || value >= 1 << (size * 8)) {
+            return Err(format!("{} doesn't fit into the {} bytes of `{}`.", value, size, field));
+        }
+        bytes[offset..offset + size].copy_from_slice(&value.to_be_bytes()[8 - size..]);
+    }
+
+    Ok((name.to_string(), bytes))
+}
+
+/// Replaces struct constants in `source`, blanks `#struct` lines and turns `#instance` lines into
+/// `#instance <name> <bytes>`, which the lexer emits as a label and a `db`.
+pub fn apply(source: &str, path: &str, layouts: &[Layout], defines: &HashMap<String, String>) -> String {
+    if layouts.is_empty() && !source.to_lowercase().contains("#instance") { return source.to_owned() }
+    let constants = constants(layouts);
+
+    source.split("\n").enumerate().map(|(n, text)| {
+        match keyword(text).as_str() {
+            "#struct" => String::new(),
+            "#instance" => match instance(text, layouts, defines) {
+                Ok((name, bytes)) => format!("#instance {} {}", name, bytes.iter().map(|b| b.to_string()).collect::<Vec<String>>().join(" ")),
+                Err(e) => { error(line(path, n, text), &e); String::new() }
+            },
+
+            _ => replace_words(text, "_.", &constants),
+        }
+    }).collect::<Vec<String>>().join("\n")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collect_errors;
+
+    #[test]
+    fn layout_constants_and_instances() {
+        let sources = vec![(String::from("a.asm"), String::from("#struct Sprite { x: 2, y: 2, w: 1, data: 4 } ; sprite"))];
+        let layouts = collect(&sources, &HashMap::new());
+        assert_eq!((layouts[0].size(), layouts[0].offset("data")), (9, Some(5)));
+
+        let source = "mva r1 2 Sprite.y r0\nmov r2 Sprite.size*2\n#instance Sprite hero { x: 10, data: &01020304 }";
+        assert_eq!(apply(source, "b.asm", &layouts, &HashMap::new()), "mva r1 2 2 r0\nmov r2 9*2\n#instance hero 0 10 0 0 0 1 2 3 4");
+    }
+
+    #[test]
+    fn struct_errors() {
+        let sources = vec![(String::from("a.asm"), String::from("#struct S { a: 2, a: 1 }\n#struct T { a: 9 }\n#struct U { a: 1 }\n#struct U { b: 1 }"))];
+        let (layouts, errors) = collect_errors(|| collect(&sources, &HashMap::new()));
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![
+            (1, "Field `a` is defined twice."),
+            (2, "Field `a` is 9 bytes, fields are 1 to 8 bytes."),
+            (4, "Struct `U` is defined twice."),
+        ]);
+
+        let (_, errors) = collect_errors(|| apply("#instance U u { a: 256 }\n#instance U u { b: 1 }\n#instance V v {}", "a.asm", &layouts, &HashMap::new()));
+        assert_eq!(errors.iter().map(|e| e.1.as_str()).collect::<Vec<_>>(), vec!["256 doesn't fit into the 1 bytes of `a`.", "`U` has no field `b`.", "Unknown struct `V`."]);
+    }
+}
@@ -0,0 +1,272 @@
+use colored::Colorize;
+use std::collections::HashMap;
+
+use crate::{asset_dir, error, lex_files, link, source_files, resolve_arg, sim::{Machine, Stop}, Arg, Control, Line};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Target {
+    Ureg(usize),
+    Freg(usize),
+    Carry,
+    Mem(usize, usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Int(u64),
+    Float(f64),
+    Bytes(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expect {
+    pub target: Target,
+    pub equal: bool,
+    pub expected: Expected,
+}
+
+fn literal(arg: &str) -> Result<u64, &'static str> {
+    match resolve_arg(arg.to_owned())? {
+        Arg::Liter(n) => Ok(u64::from_be_bytes(n[..8].try_into().unwrap())),
+
+        _ => Err("Invalid argument, expected literal."),
+    }
+}
+
+/// Parses the arguments of `#expect <target> <==|!=> <value...>` where the target is a
+/// register, `carry` or `mem[<start>..<end>]`. Memory compares against a byte list, or against
+/// a single big endian number.
+pub fn parse_expect(args: &[String]) -> Result<Expect, &'static str> {
+    if args.len() < 3 { return Err("Expected `#expect <target> == <value>`.") }
+
+    let target = if args[0] == "carry" {
+        Target::Carry
+    }
+    else if let Some(range) = args[0].strip_prefix("mem[").and_then(|r| r.strip_suffix("]")) {
+        let (start, end) = range.split_once("..").ok_or("Expected mem[<start>..<end>].")?;
+        let (start, end) = (literal(start)? as usize, literal(end)? as usize);
+        if end <= start { return Err("Empty memory range.") }
+        Target::Mem(start, end - start)
+    }
+    else {
+        match resolve_arg(args[0].clone())? {
+            Arg::Ureg(n) => Target::Ureg(n as usize),
+            Arg::Freg(n) => Target::Freg(n as usize),
+
+            _ => return Err("Invalid argument, expected register, carry or mem[<start>..<end>]."),
+        }
+    };
+
+    let equal = match args[1].as_str() {
+        "==" => true,
+        "!=" => false,
+
+        _ => return Err("Expected `==` or `!=`."),
+    };
+
+    let values = &args[2..];
+    let expected = match (&target, values.len()) {
+        (Target::Freg(_), 1) => Expected::Float(values[0].parse().map_err(|_| "Invalid float literal.")?),
+        (Target::Mem(_, _), n) if n > 1 => Expected::Bytes(values.iter().map(|v| literal(v).map(|n| n as u8)).collect::<Result<Vec<u8>, &str>>()?),
+        (_, 1) => Expected::Int(literal(&values[0])?),
+
+        _ => return Err("Expected a single value."),
+    };
+
+    if let (Target::Mem(_, len), Expected::Bytes(bytes)) = (&target, &expected) {
+        if *len != bytes.len() { return Err("Byte list length doesn't match the memory range.") }
+    }
+
+    Ok(Expect { target, equal, expected })
+}
+
+/// Checks an expectation, returns a description of the actual value on failure.
+pub fn check(expect: &Expect, machine: &Machine) -> Result<(), String> {
+    let (matches, actual) = match (&expect.target, &expect.expected) {
+        (Target::Ureg(n), Expected::Int(v)) =>      (machine.regs[*n] == *v, format!("{}", machine.regs[*n])),
+        (Target::Freg(n), Expected::Float(v)) =>    (machine.fregs[*n] == *v, format!("{}", machine.fregs[*n])),
+        (Target::Carry, Expected::Int(v)) =>        (machine.carry as u64 == *v, format!("{}", machine.carry as u8)),
+        (Target::Mem(a, l), Expected::Int(v)) =>    (machine.memory.read_be(*a, *l) == *v, format!("{}", machine.memory.read_be(*a, *l))),
+        (Target::Mem(a, l), Expected::Bytes(v)) =>  {
+            let bytes = machine.memory.read(*a, *l);
+            (bytes == *v, bytes.iter().map(|n| format!("&{:02x}", n)).collect::<Vec<String>>().join(" "))
+        }
+
+        _ => (false, String::from("?")),
+    };
+
+    if matches == expect.equal { Ok(()) } else { Err(actual) }
+}
+
+/// Removes every `#test` block, used for the normal output binary. A stray `#expect` is
+/// reported like `asm test` would.
+pub fn strip_tests(instructions: Vec<Line>) -> Vec<Line> {
+    let mut inside = false;
+
+    instructions.into_iter().filter(|inst| {
+        match inst.0 {
+            Control::Test(_) => { inside = true; false }
+            Control::EndTest => { inside = false; false }
+            Control::Expect(_) if !inside => { error(inst.clone(), "`#expect` outside of a test block."); false }
+
+            _ => !inside,
+        }
+    }).collect()
+}
+
+/// A test block as (name, `#test` line, body).
+type TestBlock = (String, Line, Vec<Line>);
+
+/// Splits the source into the program and its tests.
+fn split_tests(instructions: Vec<Line>) -> (Vec<Line>, Vec<TestBlock>) {
+    let mut program = Vec::new();
+    let mut tests: Vec<TestBlock> = Vec::new();
+    let mut inside = false;
+
+    for inst in instructions.into_iter() {
+        match &inst.0 {
+            Control::Test(name) => {
+                if inside { error(inst.clone(), "Nested test block.") }
+                tests.push((name.clone(), inst.clone(), Vec::new()));
+                inside = true;
+            }
+            Control::EndTest => {
+                if !inside { error(inst.clone(), "`#endtest` without `#test`.") }
+                inside = false;
+            }
+            Control::Expect(_) if !inside => { error(inst.clone(), "`#expect` outside of a test block.") }
+
+            _ if inside => tests.last_mut().unwrap().2.push(inst),
+            _ => program.push(inst),
+        }
+    }
+    if inside { error(tests.last().unwrap().1.clone(), "Missing `#endtest`.") }
+
+    (program, tests)
+}
+
+/// Runs one test: its body is placed at `align` followed by an implicit `hlt` and the rest of
+/// the program, so it can jump to any label. Expectations are checked when execution reaches them.
+fn run_test(program: &[Line], start: &Line, body: Vec<Line>, input_path: &str, align: usize, limit: usize) -> bool {
+    let mut instructions = body;
+    instructions.push((Control::Inst, vec![0x70], String::from("hlt"), Vec::new(), start.4.clone(), start.5));
+    instructions.extend_from_slice(program);
+
+    let (instructions, bytes) = link(instructions, input_path, align);
+
+    let mut expects: Vec<(usize, Expect, Line, bool)> = Vec::new();
+    let mut index = align;
+    for inst in instructions.iter() {
+        if let Control::Expect(expect) = &inst.0 { expects.push((index, expect.clone(), inst.clone(), false)) }
+        index += inst.1.len();
+    }
+
+    let mut machine = Machine::new(&bytes, align, align);
+    let mut passed = true;
+
+    let stop = loop {
+        for (address, expect, line, reached) in expects.iter_mut() {
+            if *address != machine.pc { continue }
+            *reached = true;
+
+            if let Err(actual) = check(expect, &machine) {
+                error(line.clone(), &format!("Expectation failed, got {}.", actual));
+                passed = false;
+            }
+        }
+
+        if machine.cycles >= limit { break Stop::Limit }
+        match machine.step() {
+            Ok(true) => break Stop::Halted,
+            Ok(false) => {}
+            Err(e) => break Stop::Fault(e),
+        }
+    };
+
+    match stop {
+        Stop::Halted => {}
+        Stop::Limit => { error(start.clone(), &format!("Test didn't halt within {} instructions.", limit)); passed = false }
+        Stop::Fault(e) => { error(start.clone(), &e); passed = false }
+    }
+    for (_, _, line, reached) in expects.iter() {
+        if !reached { error(line.clone(), "Expectation was never reached."); passed = false }
+    }
+
+    passed
+}
+
+/// Runs every `#test` block in `input_path`, returns whether all of them passed.
+pub fn run_tests(input_path: &str, include: &[String], defines: &HashMap<String, String>, align: usize, limit: usize) -> bool {
+    let paths = match source_files(input_path, include) {
+        Ok(paths) => paths,
+        Err(e) => { error((Control::None, Vec::new(), String::new(), Vec::new(), input_path.to_owned(), 0), &e); return false }
+    };
+    let (program, tests) = split_tests(lex_files(paths, defines));
+    let input_path = asset_dir(input_path);
+
+    let mut failed = 0;
+    for (name, start, body) in tests.iter() {
+        let passed = run_test(&program, start, body.clone(), &input_path, align, limit);
+        if !passed { failed += 1 }
+
+        println!("test {} ... {}", name, if passed { "ok".green() } else { "FAILED".red() });
+    }
+
+    println!("\ntest result: {}. {} passed; {} failed", if failed == 0 { "ok".green() } else { "FAILED".red() }, tests.len() - failed, failed);
+    failed == 0
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{collect_errors, lex_source};
+
+    fn args(s: &str) -> Vec<String> {
+        s.split(" ").map(|s| s.to_owned()).collect()
+    }
+
+    #[test]
+    fn parse_expectations() {
+        assert_eq!(parse_expect(&args("r0 == 5")).unwrap(), Expect { target: Target::Ureg(0), equal: true, expected: Expected::Int(5) });
+        assert_eq!(parse_expect(&args("f1 != 1.5")).unwrap(), Expect { target: Target::Freg(1), equal: false, expected: Expected::Float(1.5) });
+        assert_eq!(parse_expect(&args("mem[&1000..&1002] == &01 &02")).unwrap().expected, Expected::Bytes(vec![1, 2]));
+        assert!(parse_expect(&args("mem[&1000..&1002] == &01 &02 &03")).is_err());
+        assert!(parse_expect(&args("r0 < 5")).is_err());
+    }
+
+    #[test]
+    fn tests_are_stripped_and_run() {
+        let source = indoc::indoc! {"
+            double:
+            add r0 r0 r0
+            hlt
+
+            #test doubles
+            mov r0 21
+            add r0 r0 r0
+            #expect r0 == 42
+            #expect r0 != 41
+            #endtest
+
+            #test fails
+            #expect r0 == 1
+            #endtest
+        "};
+        let instructions = lex_source(source, "test.asm");
+
+        assert_eq!(strip_tests(instructions.clone()).len(), 3);
+
+        let (program, tests) = split_tests(instructions);
+        assert_eq!(tests.len(), 2);
+        assert!(run_test(&program, &tests[0].1, tests[0].2.clone(), ".", 0x1000, 1000));
+        assert!(!run_test(&program, &tests[1].1, tests[1].2.clone(), ".", 0x1000, 1000));
+    }
+
+    #[test]
+    fn stray_expect() {
+        let (instructions, errors) = collect_errors(|| strip_tests(lex_source("mov r0 1\n#expect r0 == 1\nhlt\n", "test.asm")));
+        assert_eq!(instructions.len(), 2);
+        assert_eq!(errors.iter().map(|e| (e.0.5, e.1.as_str())).collect::<Vec<_>>(), vec![(2, "`#expect` outside of a test block.")]);
+    }
+}
@@ -0,0 +1,77 @@
+use colored::Colorize;
+use std::{collections::HashMap, fs, path::PathBuf, thread, time::{Duration, SystemTime}};
+
+use crate::{asset_dir, reset_errors, source_files, Control, Line};
+
+/// How often the watched files are polled.
+pub const INTERVAL: Duration = Duration::from_millis(300);
+
+/// Modification time of every watched file, `None` once it's gone.
+type Stamps = HashMap<PathBuf, Option<SystemTime>>;
+
+/// `#image` and `#bytes` files referenced by the instructions.
+pub fn assets(instructions: &[Line], input_path: &str) -> Vec<PathBuf> {
+    let dir = asset_dir(input_path);
+
+    instructions.iter().filter_map(|inst| {
+        match &inst.0 {
+            Control::ImgDataPointer(path) | Control::DataPointer(path) => Some(PathBuf::from(format!("{}/{}", dir, path))),
+
+            _ => None,
+        }
+    }).collect()
+}
+
+fn stamps(paths: &[PathBuf]) -> Stamps {
+    paths.iter().map(|p| (p.clone(), fs::metadata(p).and_then(|m| m.modified()).ok())).collect()
+}
+
+/// Runs `build` and again every time a file below the input or include folders, or one of the
+/// assets it references, is added, removed or modified. Never returns, stop it with Ctrl-C.
+pub fn watch(input_path: &str, include: &[String], mut build: impl FnMut() -> Vec<Line>) -> ! {
+    loop {
+        reset_errors();
+        let assets = assets(&build(), input_path);
+
+        // A folder that can't be read, like one deleted or renamed, was reported by `build`
+        // and is watched until it's back.
+        let files = || {
+            let mut files = source_files(input_path, include)?;
+            files.extend(assets.iter().cloned());
+            Ok::<Vec<PathBuf>, String>(files)
+        };
+
+        let before = files().map(|f| stamps(&f));
+        match &before {
+            Ok(before) => println!("{}", format!("Watching {} files, Ctrl-C to stop.", before.len()).bright_cyan().bold()),
+            Err(_) =>     println!("{}", "Watching for the folder to come back, Ctrl-C to stop.".bright_cyan().bold()),
+        }
+
+        while files().map(|f| stamps(&f)) == before {
+            thread::sleep(INTERVAL);
+        }
+        println!("\n{}", "Change detected, reassembling.".bright_cyan().bold());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lex_source;
+
+    #[test]
+    fn assets_and_stamps() {
+        let instructions = lex_source("#image logo art/logo.png\n#bytes font font.bin\nhlt\n", "main.asm");
+        assert_eq!(assets(&instructions, "game"), vec![PathBuf::from("game/art/logo.png"), PathBuf::from("game/font.bin")]);
+
+        let path = std::env::temp_dir().join(format!("vm64-watch-{}", std::process::id()));
+        fs::write(&path, "a").unwrap();
+        let before = stamps(std::slice::from_ref(&path));
+        fs::remove_file(&path).unwrap();
+
+        assert!(before[&path].is_some());
+        assert_ne!(stamps(std::slice::from_ref(&path)), before);
+        assert_eq!(source_files(&path.display().to_string(), &[]), Err(format!("Unable to read folder `{}`.", path.display())));
+    }
+}